@@ -1,45 +1,96 @@
-use teloxide::prelude::*;
-use crate::utils::{encode, encode_file_id, get_random_emoji};
-use crate::models::{StateStorage, set_user_state, get_user_state, clear_user_state, BotState};
+use teloxide::{prelude::*, types::User, ApiError, RequestError};
+use crate::utils::{encode, encode_album, encode_content, get_random_emoji, CallbackAction, EmojiCategory, SharedContent};
+use crate::models::{BotConfig, DbClient, StateKey, InlinePayloadStorage, KeyboardOwnerStorage, keyboard_owner, load_chat_settings, load_inline_payload, load_whisper, load_user_emojis, record_recent_emoji, StateStorage, MediaGroupStorage, set_user_state, get_user_state, clear_user_state, get_media_group, BotState};
+use crate::handlers::carrier::CUSTOM_CARRIER_PROMPT;
+use crate::handlers::commands::create_emoji_keyboard;
+use crate::handlers::media::{encode_and_store_file, extract_file_info, extract_shared_content, FileInfo};
 
-pub async fn callback_handler(bot: Bot, q: CallbackQuery, state_storage: StateStorage) -> ResponseResult<()> {
+/// Shown for buttons the bot can no longer act on
+const EXPIRED_BUTTON_ALERT: &str = "⌛ This button has expired. Please send your message again.";
+
+pub async fn callback_handler(
+    bot: Bot,
+    q: CallbackQuery,
+    state_storage: StateStorage,
+    media_groups: MediaGroupStorage,
+    db: Option<DbClient>,
+    config: BotConfig,
+    payloads: InlinePayloadStorage,
+) -> ResponseResult<()> {
     let user_id = q.from.id.0 as i64;
-    let state = get_user_state(&state_storage, user_id).await;
-
-    if let Some(data) = &q.data {
-        let parts: Vec<&str> = data.split(':').collect();
-
-        match parts[0] {
-            "encode" if parts.len() == 2 => {
-                let emoji = parts[1];
-                // Check if we're encoding a file or text
-                match state {
-                    BotState::AwaitingFileEmoji { file_id, .. } => {
-                        handle_file_encode(&bot, &q, emoji, &file_id, &state_storage, user_id).await?;
-                    }
-                    _ => {
-                        // Try file first, fallback to text
-                        handle_encode_with_file_check(&bot, &q, emoji, &state_storage, user_id).await?;
-                    }
+    // Flows continue in the chat and topic of the keyboard, never elsewhere
+    let state = match q.message.as_ref().and_then(|m| m.regular_message()) {
+        Some(keyboard) => get_user_state(&state_storage, &StateKey::new(keyboard, user_id)).await,
+        None => BotState::Idle,
+    };
+
+    let action = match q.data.as_deref().map(CallbackAction::parse) {
+        Some(Ok(action)) => action,
+        // Buttons from an older version of the bot, or data we never sent
+        _ => {
+            bot.answer_callback_query(q.id.clone())
+                .text(EXPIRED_BUTTON_ALERT)
+                .show_alert(true)
+                .await?;
+            return Ok(());
+        }
+    };
+
+    match action {
+        CallbackAction::Encode(emoji) => {
+            let emoji = emoji.as_str();
+            // Check if we're encoding a file or text
+            match state {
+                BotState::AwaitingFileEmoji { file_id, file_type, file_unique_id, origin } => {
+                    let info = FileInfo { file_id, file_unique_id, file_type, origin };
+                    handle_file_encode(&bot, &q, emoji, &info, &state_storage, &db, &config).await?;
                 }
-            }
-            "random" => {
-                let emoji = get_random_emoji();
-                // Check if we're encoding a file or text
-                match state {
-                    BotState::AwaitingFileEmoji { file_id, .. } => {
-                        handle_file_encode(&bot, &q, emoji, &file_id, &state_storage, user_id).await?;
-                    }
-                    _ => {
-                        // Try file first, fallback to text
-                        handle_encode_with_file_check(&bot, &q, emoji, &state_storage, user_id).await?;
-                    }
+                BotState::AwaitingAlbumEmoji { file_ids } => {
+                    handle_album_encode(&bot, &q, emoji, &file_ids, &state_storage, user_id).await?;
+                }
+                BotState::AwaitingContentEmoji { content } => {
+                    handle_content_encode(&bot, &q, emoji, &content, &state_storage, user_id).await?;
+                }
+                _ => {
+                    // Try file first, fallback to text
+                    handle_encode_with_file_check(&bot, &q, emoji, &state_storage, &media_groups, &db, &config).await?;
                 }
             }
-            "custom" => {
-                handle_custom(&bot, &q, &state_storage).await?;
+            record_recent_emoji(&db, user_id, emoji).await;
+        }
+        CallbackAction::Random => {
+            let emoji = get_random_emoji();
+            // Check if we're encoding a file or text
+            match state {
+                BotState::AwaitingFileEmoji { file_id, file_type, file_unique_id, origin } => {
+                    let info = FileInfo { file_id, file_unique_id, file_type, origin };
+                    handle_file_encode(&bot, &q, emoji, &info, &state_storage, &db, &config).await?;
+                }
+                BotState::AwaitingAlbumEmoji { file_ids } => {
+                    handle_album_encode(&bot, &q, emoji, &file_ids, &state_storage, user_id).await?;
+                }
+                BotState::AwaitingContentEmoji { content } => {
+                    handle_content_encode(&bot, &q, emoji, &content, &state_storage, user_id).await?;
+                }
+                _ => {
+                    // Try file first, fallback to text
+                    handle_encode_with_file_check(&bot, &q, emoji, &state_storage, &media_groups, &db, &config).await?;
+                }
             }
-            _ => {}
+        }
+        CallbackAction::Custom => {
+            handle_custom(&bot, &q, &state_storage, &media_groups).await?;
+        }
+        CallbackAction::Pick { category, page } => {
+            handle_picker_page(&bot, &q, category, page, &db).await?;
+        }
+        CallbackAction::Noop => {}
+        CallbackAction::Reveal(key) => {
+            // Answers the query itself with an alert
+            return handle_reveal(&bot, &q, &key, &payloads, &db).await;
+        }
+        CallbackAction::Whisper(key) => {
+            return handle_whisper_reveal(&bot, &q, &key, &payloads, &db).await;
         }
     }
 
@@ -47,18 +98,224 @@ pub async fn callback_handler(bot: Bot, q: CallbackQuery, state_storage: StateSt
     Ok(())
 }
 
+/// Who may press a keyboard button, before any admin is asked about
+#[derive(Debug, PartialEq)]
+enum KeyboardPress {
+    /// Buttons for everyone, or the keyboard's owner
+    Allowed,
+    /// Nobody knows who the keyboard was sent to anymore
+    Unknown,
+    /// Someone else's keyboard
+    NotOwner(User),
+}
+
+/// Check a press against the keyboard's owner
+/// Private chats only have one user, so keyboards there never need an owner
+fn check_keyboard_press(keyboard: &Message, owner: Option<User>, presser: &User) -> KeyboardPress {
+    match owner {
+        Some(owner) if owner.id == presser.id => KeyboardPress::Allowed,
+        Some(owner) => KeyboardPress::NotOwner(owner),
+        None if keyboard.chat.is_private() => KeyboardPress::Allowed,
+        None => KeyboardPress::Unknown,
+    }
+}
+
+/// An admin's press, handled as if the keyboard's owner made it
+fn act_for_owner(q: CallbackQuery, owner: User) -> CallbackQuery {
+    CallbackQuery { from: owner, ..q }
+}
+
+/// Check that the user pressing a keyboard button is the one it was sent to
+/// Returns the query to handle, acting for the keyboard's owner when a group lets
+/// its admins press for them, or None after telling anyone else off
+pub async fn authorize_keyboard_press(
+    bot: &Bot,
+    q: CallbackQuery,
+    keyboard_owners: &KeyboardOwnerStorage,
+    db: &Option<DbClient>,
+) -> ResponseResult<Option<CallbackQuery>> {
+    // Buttons on inline-sent messages, like Reveal, are meant for everyone
+    let Some(keyboard) = q.message.as_ref().and_then(|m| m.regular_message()) else {
+        return Ok(Some(q));
+    };
+    let owner = keyboard_owner(keyboard_owners, db, keyboard).await;
+
+    let alert = match check_keyboard_press(keyboard, owner, &q.from) {
+        KeyboardPress::Allowed => return Ok(Some(q)),
+        // Fail closed, anyone could press a keyboard whose owner was forgotten
+        KeyboardPress::Unknown => EXPIRED_BUTTON_ALERT.to_string(),
+        KeyboardPress::NotOwner(owner) => {
+            let chat_id = keyboard.chat.id;
+            if load_chat_settings(db, chat_id.0).await.admins_can_press && is_chat_admin(bot, chat_id, q.from.id).await {
+                return Ok(Some(act_for_owner(q, owner)));
+            }
+            format!("🚫 These buttons are for {}. Send your own message to get a keyboard.", owner.first_name)
+        }
+    };
+
+    bot.answer_callback_query(q.id.clone())
+        .text(alert)
+        .show_alert(true)
+        .await?;
+    Ok(None)
+}
+
+/// Whether a user administers a chat, false if Telegram can't tell us
+pub async fn is_chat_admin(bot: &Bot, chat_id: ChatId, user_id: UserId) -> bool {
+    match bot.get_chat_member(chat_id, user_id).await {
+        Ok(member) => member.is_privileged(),
+        Err(e) => {
+            log::error!("Failed to look up member {} of chat {}: {}", user_id, chat_id, e);
+            false
+        }
+    }
+}
+
+/// Switch the emoji picker to another category or page
+/// Only the keyboard changes, so the message it replies to stays the encode context
+async fn handle_picker_page(
+    bot: &Bot,
+    q: &CallbackQuery,
+    category: EmojiCategory,
+    page: usize,
+    db: &Option<DbClient>,
+) -> ResponseResult<()> {
+    let Some(msg) = q.message.as_ref().and_then(|m| m.regular_message()) else {
+        return Ok(());
+    };
+
+    let user_emojis = load_user_emojis(db, q.from.id.0 as i64).await;
+    let edited = bot
+        .edit_message_reply_markup(msg.chat.id, msg.id)
+        .reply_markup(create_emoji_keyboard(&user_emojis, category, page))
+        .await;
+
+    match edited {
+        // Tapping the tab that is already open
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Show the secret behind an inline-sent emoji in an alert
+async fn handle_reveal(
+    bot: &Bot,
+    q: &CallbackQuery,
+    key: &str,
+    payloads: &InlinePayloadStorage,
+    db: &Option<DbClient>,
+) -> ResponseResult<()> {
+    let text = match load_inline_payload(payloads, db, key).await {
+        Some(secret) => alert_text(&format!("🔓 {}", secret)),
+        None => "⌛ This secret is no longer available. Forward the emoji to the bot to decode it.".to_string(),
+    };
+
+    bot.answer_callback_query(q.id.clone())
+        .text(text)
+        .show_alert(true)
+        .await?;
+    Ok(())
+}
+
+/// Show a whisper in an alert, but only to its recipients and author
+async fn handle_whisper_reveal(
+    bot: &Bot,
+    q: &CallbackQuery,
+    key: &str,
+    payloads: &InlinePayloadStorage,
+    db: &Option<DbClient>,
+) -> ResponseResult<()> {
+    let text = match load_whisper(payloads, db, key).await {
+        Some(whisper) if whisper.can_read(&q.from) => alert_text(&format!("🤫 {}", whisper.text)),
+        Some(_) => "🙈 This whisper isn't for you.".to_string(),
+        None => "⌛ This whisper is no longer available.".to_string(),
+    };
+
+    bot.answer_callback_query(q.id.clone())
+        .text(text)
+        .show_alert(true)
+        .await?;
+    Ok(())
+}
+
+/// Fit text into a callback alert, which holds at most 200 characters
+fn alert_text(text: &str) -> String {
+    const ALERT_LIMIT: usize = 200;
+    const TRUNCATED_NOTE: &str = "… (forward the emoji to the bot to read all)";
+
+    if text.chars().count() <= ALERT_LIMIT {
+        return text.to_string();
+    }
+
+    let keep = ALERT_LIMIT - TRUNCATED_NOTE.chars().count();
+    format!("{}{}", text.chars().take(keep).collect::<String>(), TRUNCATED_NOTE)
+}
+
 async fn handle_file_encode(
     bot: &Bot,
     q: &CallbackQuery,
     emoji: &str,
-    file_id: &str,
+    info: &FileInfo,
+    state_storage: &StateStorage,
+    db: &Option<DbClient>,
+    config: &BotConfig,
+) -> ResponseResult<()> {
+    let user_id = q.from.id.0 as i64;
+
+    if let Some(msg) = q.message.as_ref().and_then(|m| m.regular_message()) {
+        clear_user_state(state_storage, &StateKey::new(msg, user_id)).await;
+
+        match encode_and_store_file(bot, db, config, user_id, info, emoji).await {
+            Ok(encoded) => {
+                bot.edit_message_text(msg.chat.id, msg.id, &encoded).await?;
+            }
+            Err(e) => {
+                bot.send_message(msg.chat.id, format!("❌ Error encoding: {}", e))
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encode a collected album and replace the keyboard message with the result
+async fn handle_album_encode(
+    bot: &Bot,
+    q: &CallbackQuery,
+    emoji: &str,
+    file_ids: &[String],
+    state_storage: &StateStorage,
+    user_id: i64,
+) -> ResponseResult<()> {
+    if let Some(msg) = q.message.as_ref().and_then(|m| m.regular_message()) {
+        clear_user_state(state_storage, &StateKey::new(msg, user_id)).await;
+
+        match encode_album(emoji, file_ids) {
+            Ok(encoded) => {
+                bot.edit_message_text(msg.chat.id, msg.id, &encoded).await?;
+            }
+            Err(e) => {
+                bot.send_message(msg.chat.id, format!("❌ Error encoding: {}", e))
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encode a location, contact, poll or dice and replace the keyboard message with the result
+async fn handle_content_encode(
+    bot: &Bot,
+    q: &CallbackQuery,
+    emoji: &str,
+    content: &SharedContent,
     state_storage: &StateStorage,
     user_id: i64,
 ) -> ResponseResult<()> {
     if let Some(msg) = q.message.as_ref().and_then(|m| m.regular_message()) {
-        clear_user_state(state_storage, user_id).await;
+        clear_user_state(state_storage, &StateKey::new(msg, user_id)).await;
 
-        match encode_file_id(emoji, file_id) {
+        match encode_content(emoji, content) {
             Ok(encoded) => {
                 bot.edit_message_text(msg.chat.id, msg.id, &encoded).await?;
             }
@@ -71,57 +328,70 @@ async fn handle_file_encode(
     Ok(())
 }
 
-async fn handle_custom(bot: &Bot, q: &CallbackQuery, state_storage: &StateStorage) -> ResponseResult<()> {
+/// Look up the collected album a replied message belongs to
+async fn find_album(media_groups: &MediaGroupStorage, msg: &Message) -> Option<Vec<String>> {
+    let media_group_id = msg.media_group_id()?;
+    get_media_group(media_groups, &media_group_id.0)
+        .await
+        .map(|group| group.file_ids())
+}
+
+async fn handle_custom(
+    bot: &Bot,
+    q: &CallbackQuery,
+    state_storage: &StateStorage,
+    media_groups: &MediaGroupStorage,
+) -> ResponseResult<()> {
     if let Some(msg) = q.message.as_ref().and_then(|m| m.regular_message()) {
         let user_id = q.from.id.0 as i64;
-        let current_state = get_user_state(state_storage, user_id).await;
+        let state_key = StateKey::new(msg, user_id);
+        let current_state = get_user_state(state_storage, &state_key).await;
 
         // Check if we're in file encoding mode
-        if let BotState::AwaitingFileEmoji { file_id, file_type } = current_state {
+        if let BotState::AwaitingFileEmoji { file_id, file_type, file_unique_id, origin } = current_state {
             // Keep the file info but wait for custom emoji
             let state = BotState::AwaitingFileEmoji {
                 file_id,
                 file_type,
+                file_unique_id,
+                origin,
             };
-            set_user_state(state_storage, user_id, state).await;
-
-            bot.edit_message_text(
-                msg.chat.id,
-                msg.id,
-                "Please send me the emoji you want to use for encoding:",
-            )
-            .await?;
+            set_user_state(state_storage, &state_key, state).await;
+
+            bot.edit_message_text(msg.chat.id, msg.id, CUSTOM_CARRIER_PROMPT).await?;
         } else {
             // Handle both text and file encoding
             if let Some(reply_to_msg) = msg.reply_to_message() {
-                // Check if it's a file first
-                if let Some((file_id, file_type)) = extract_file_info_from_callback(reply_to_msg) {
+                // Check if it's an album or a file first
+                if let Some(file_ids) = find_album(media_groups, reply_to_msg).await {
+                    let state = BotState::AwaitingAlbumEmoji { file_ids };
+                    set_user_state(state_storage, &state_key, state).await;
+
+                    bot.edit_message_text(msg.chat.id, msg.id, CUSTOM_CARRIER_PROMPT).await?;
+                } else if let Some(info) = extract_file_info(reply_to_msg) {
                     // It's a file, set state accordingly
                     let state = BotState::AwaitingFileEmoji {
-                        file_id,
-                        file_type,
+                        file_id: info.file_id,
+                        file_type: info.file_type,
+                        file_unique_id: info.file_unique_id,
+                        origin: info.origin,
                     };
-                    set_user_state(state_storage, user_id, state).await;
+                    set_user_state(state_storage, &state_key, state).await;
 
-                    bot.edit_message_text(
-                        msg.chat.id,
-                        msg.id,
-                        "Please send me the emoji you want to use for encoding:",
-                    )
-                    .await?;
+                    bot.edit_message_text(msg.chat.id, msg.id, CUSTOM_CARRIER_PROMPT).await?;
+                } else if let Some(content) = extract_shared_content(reply_to_msg) {
+                    let state = BotState::AwaitingContentEmoji { content };
+                    set_user_state(state_storage, &state_key, state).await;
+
+                    bot.edit_message_text(msg.chat.id, msg.id, CUSTOM_CARRIER_PROMPT).await?;
                 } else if let Some(text) = reply_to_msg.text() {
                     // It's a text message
                     let state = BotState::AwaitingCustomEmoji {
                         text: text.to_string(),
                     };
-                    set_user_state(state_storage, user_id, state).await;
+                    set_user_state(state_storage, &state_key, state).await;
 
-                    bot.edit_message_text(
-                        msg.chat.id,
-                        msg.id,
-                        "Please send me the emoji you want to use for encoding:",
-                    )
-                    .await?;
+                    bot.edit_message_text(msg.chat.id, msg.id, CUSTOM_CARRIER_PROMPT).await?;
                 } else {
                     bot.send_message(msg.chat.id, "❌ Could not find the original text or file message.")
                         .await?;
@@ -141,13 +411,21 @@ async fn handle_encode_with_file_check(
     q: &CallbackQuery,
     emoji: &str,
     state_storage: &StateStorage,
-    user_id: i64,
+    media_groups: &MediaGroupStorage,
+    db: &Option<DbClient>,
+    config: &BotConfig,
 ) -> ResponseResult<()> {
+    let user_id = q.from.id.0 as i64;
+
     if let Some(msg) = q.message.as_ref().and_then(|m| m.regular_message()) {
         if let Some(reply_to_msg) = msg.reply_to_message() {
-            // Check if it's a file first
-            if let Some((file_id, _file_type)) = extract_file_info_from_callback(reply_to_msg) {
-                handle_file_encode(bot, q, emoji, &file_id, state_storage, user_id).await?;
+            // Check if it's an album or a file first
+            if let Some(file_ids) = find_album(media_groups, reply_to_msg).await {
+                handle_album_encode(bot, q, emoji, &file_ids, state_storage, user_id).await?;
+            } else if let Some(info) = extract_file_info(reply_to_msg) {
+                handle_file_encode(bot, q, emoji, &info, state_storage, db, config).await?;
+            } else if let Some(content) = extract_shared_content(reply_to_msg) {
+                handle_content_encode(bot, q, emoji, &content, state_storage, user_id).await?;
             } else if let Some(text) = reply_to_msg.text() {
                 // It's a text message
                 match encode(emoji, text) {
@@ -171,41 +449,75 @@ async fn handle_encode_with_file_check(
     Ok(())
 }
 
-/// Extract file_id and file_type from a message (for callbacks)
-fn extract_file_info_from_callback(msg: &Message) -> Option<(String, String)> {
-    if let Some(photo) = msg.photo() {
-        if let Some(largest) = photo.last() {
-            return Some((largest.file.id.to_string(), "photo".to_string()));
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if let Some(video) = msg.video() {
-        return Some((video.file.id.to_string(), "video".to_string()));
+    fn user(id: u64, name: &str) -> User {
+        serde_json::from_value(serde_json::json!({ "id": id, "is_bot": false, "first_name": name })).unwrap()
     }
 
-    if let Some(audio) = msg.audio() {
-        return Some((audio.file.id.to_string(), "audio".to_string()));
+    /// A keyboard the bot sent to a chat
+    fn keyboard(chat: serde_json::Value) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 20,
+            "date": 1_700_000_000,
+            "chat": chat,
+            "from": { "id": 1, "is_bot": true, "first_name": "Bot" },
+            "text": "Select an emoji to hide your text:",
+        }))
+        .unwrap()
     }
 
-    if let Some(document) = msg.document() {
-        return Some((document.file.id.to_string(), "document".to_string()));
+    fn group_keyboard() -> Message {
+        keyboard(serde_json::json!({ "id": -1001, "type": "supergroup", "title": "Group" }))
     }
 
-    if let Some(sticker) = msg.sticker() {
-        return Some((sticker.file.id.to_string(), "sticker".to_string()));
+    #[test]
+    fn test_owner_may_press() {
+        let alice = user(7, "Alice");
+        assert_eq!(check_keyboard_press(&group_keyboard(), Some(alice.clone()), &alice), KeyboardPress::Allowed);
     }
 
-    if let Some(voice) = msg.voice() {
-        return Some((voice.file.id.to_string(), "voice".to_string()));
+    #[test]
+    fn test_others_may_not_press() {
+        let alice = user(7, "Alice");
+        let bob = user(8, "Bob");
+        assert_eq!(
+            check_keyboard_press(&group_keyboard(), Some(alice.clone()), &bob),
+            KeyboardPress::NotOwner(alice)
+        );
     }
 
-    if let Some(video_note) = msg.video_note() {
-        return Some((video_note.file.id.to_string(), "video note".to_string()));
-    }
+    #[test]
+    fn test_admin_acts_for_owner() {
+        let alice = user(7, "Alice");
+        let admin = user(9, "Admin");
+
+        // Admins are other users too, only the group setting lets them through
+        assert_eq!(
+            check_keyboard_press(&group_keyboard(), Some(alice.clone()), &admin),
+            KeyboardPress::NotOwner(alice.clone())
+        );
 
-    if let Some(animation) = msg.animation() {
-        return Some((animation.file.id.to_string(), "animation".to_string()));
+        let q: CallbackQuery = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "from": { "id": 9, "is_bot": false, "first_name": "Admin" },
+            "chat_instance": "chat",
+            "data": "e:🔥",
+        }))
+        .unwrap();
+        let q = act_for_owner(q, alice.clone());
+        assert_eq!(q.from, alice);
+        assert_eq!(q.data.as_deref(), Some("e:🔥"));
     }
 
-    None
+    #[test]
+    fn test_unknown_owner_fails_closed() {
+        let bob = user(8, "Bob");
+        assert_eq!(check_keyboard_press(&group_keyboard(), None, &bob), KeyboardPress::Unknown);
+
+        let private = keyboard(serde_json::json!({ "id": 8, "type": "private", "first_name": "Bob" }));
+        assert_eq!(check_keyboard_press(&private, None, &bob), KeyboardPress::Allowed);
+    }
 }
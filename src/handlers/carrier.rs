@@ -0,0 +1,119 @@
+use teloxide::prelude::*;
+use teloxide::types::{CustomEmojiId, MessageEntity, MessageEntityKind};
+use crate::utils::{find_emoji, search_emojis, single_emoji};
+
+// Number of emojis suggested for a keyword without an exact match
+const SUGGESTION_COUNT: usize = 8;
+
+/// Asks for the carrier when the user chooses a custom emoji
+pub const CUSTOM_CARRIER_PROMPT: &str =
+    "Please send me the emoji you want to use for encoding (a sticker or custom emoji works too), or a word like fire (/cancel to stop):";
+
+/// Sent when a reply in the custom emoji step can't be used as a carrier
+pub const INVALID_CARRIER_PROMPT: &str =
+    "Please send exactly one emoji, a sticker, a Premium custom emoji or a word like fire (/cancel to stop):";
+
+/// The emoji an encoded message starts with
+/// A Premium custom emoji is a placeholder emoji shown as the custom emoji of the entity
+#[derive(Debug, Clone, PartialEq)]
+pub struct Carrier {
+    pub emoji: String,
+    pub custom_emoji_id: Option<CustomEmojiId>,
+}
+
+impl Carrier {
+    pub fn new(emoji: impl Into<String>) -> Self {
+        Self {
+            emoji: emoji.into(),
+            custom_emoji_id: None,
+        }
+    }
+
+    /// Entities to send with a message that starts with this carrier
+    pub fn entities(&self) -> Vec<MessageEntity> {
+        match &self.custom_emoji_id {
+            // Offsets and lengths count UTF-16 code units
+            Some(id) => vec![MessageEntity::custom_emoji(id.clone(), 0, self.emoji.encode_utf16().count())],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// What a reply in the custom emoji step means
+pub enum CarrierChoice {
+    Carrier(Carrier),
+    /// A word without an exact match, the user should pick one of these
+    Suggestions(Vec<&'static str>),
+    Invalid,
+}
+
+/// Read the carrier from a reply in the custom emoji step
+/// Accepts exactly one emoji, a Premium custom emoji, a sticker or a word like `fire` or `:pizza:`
+pub fn resolve_carrier(msg: &Message) -> CarrierChoice {
+    if let Some(sticker) = msg.sticker() {
+        return match &sticker.emoji {
+            Some(emoji) => CarrierChoice::Carrier(Carrier {
+                emoji: emoji.clone(),
+                custom_emoji_id: sticker.custom_emoji_id().cloned(),
+            }),
+            None => CarrierChoice::Invalid,
+        };
+    }
+
+    let Some(text) = msg.text() else {
+        return CarrierChoice::Invalid;
+    };
+    let text = text.trim();
+
+    // A custom emoji arrives as its placeholder emoji with an entity covering it
+    let custom_emoji = msg.parse_entities().unwrap_or_default().into_iter().find_map(|entity| match entity.kind() {
+        MessageEntityKind::CustomEmoji { custom_emoji_id } if entity.text() == text => Some(custom_emoji_id.clone()),
+        _ => None,
+    });
+    if let Some(custom_emoji_id) = custom_emoji {
+        return CarrierChoice::Carrier(Carrier {
+            emoji: text.to_string(),
+            custom_emoji_id: Some(custom_emoji_id),
+        });
+    }
+
+    if let Some(emoji) = single_emoji(text).or_else(|| find_emoji(text)) {
+        return CarrierChoice::Carrier(Carrier::new(emoji));
+    }
+
+    // Only a single word is looked up, sentences are rejected
+    if text.contains(char::is_whitespace) {
+        return CarrierChoice::Invalid;
+    }
+    let suggestions: Vec<_> = search_emojis(text).into_iter().take(SUGGESTION_COUNT).collect();
+    if suggestions.is_empty() {
+        CarrierChoice::Invalid
+    } else {
+        CarrierChoice::Suggestions(suggestions)
+    }
+}
+
+/// A Premium custom emoji the argument of a command starts with, and the rest of the argument
+/// As in `/encode <custom emoji> secret`
+pub fn leading_custom_emoji<'a>(msg: &Message, argument: &'a str) -> Option<(Carrier, &'a str)> {
+    let argument = argument.trim_start();
+    let argument_start = msg.text()?.len().checked_sub(argument.len())?;
+
+    msg.parse_entities()?.into_iter().find_map(|entity| match entity.kind() {
+        MessageEntityKind::CustomEmoji { custom_emoji_id } if entity.start() == argument_start => {
+            let carrier = Carrier {
+                emoji: entity.text().to_string(),
+                custom_emoji_id: Some(custom_emoji_id.clone()),
+            };
+            Some((carrier, argument.get(entity.len()..)?.trim_start()))
+        }
+        _ => None,
+    })
+}
+
+/// Send an encoded message, keeping a custom emoji carrier animated
+pub async fn send_encoded(bot: &Bot, chat_id: ChatId, encoded: &str, carrier: &Carrier) -> ResponseResult<Message> {
+    bot.send_message(chat_id, encoded)
+        .entities(carrier.entities())
+        .await
+}
@@ -1,6 +1,17 @@
-use teloxide::{prelude::*, types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, FileId}};
-use crate::utils::{EMOJI_LIST, encode, encode_file_id, decode_with_file_check, get_random_emoji, decode_file_type, FileType};
-use crate::models::DbClient;
+use teloxide::{prelude::*, types::{InlineKeyboardButton, InlineKeyboardMarkup}};
+use crate::utils::{CallbackAction, EmojiCategory, encode, find_emoji, split_leading_emoji, encode_album, encode_content, decode_payload, get_random_emoji, has_variation_selectors, DecodedPayload};
+use crate::models::{BotConfig, DbClient, KeyboardOwnerStorage, MediaGroupStorage, StateKey, StateStorage, clear_user_state, record_keyboard_owner, UserEmojis, FAVORITES_LIMIT, get_media_group, load_chat_settings, load_user_emojis, save_chat_settings};
+use crate::handlers::callbacks::is_chat_admin;
+use crate::handlers::carrier::{leading_custom_emoji, send_encoded, Carrier};
+use crate::handlers::media::{extract_file_info, extract_shared_content, canonical_file_id, describe_file_id, describe_file_unique_id, encode_and_store_file, handle_decode_file, handle_decode_album, handle_decode_content};
+
+// Emoji buttons per keyboard row
+const KEYBOARD_COLUMNS: usize = 5;
+// Rows of emojis on one page of the picker
+const KEYBOARD_ROWS: usize = 4;
+
+/// Reply to a password protected message that was sent without its password
+pub const LOCKED_MESSAGE_HINT: &str = "🔒 This message is password protected. Reply to it with /decode <password> to read it.";
 
 pub async fn start_handler(bot: Bot, msg: Message, db: DbClient) -> ResponseResult<()> {
     let chat_id = msg.chat.id.0;
@@ -64,16 +75,22 @@ pub async fn help_handler(bot: Bot, msg: Message) -> ResponseResult<()> {
         • Send encoded emoji to automatically decode and reveal content\n\n\
         *Supported File Types:*\n\
         📷 Photos • 🎬 Videos • 🎵 Audio • 📄 Documents\n\
-        🎭 Stickers • 🎤 Voice • 🎥 Video Notes • 🎞️ Animations\n\n\
+        🎭 Stickers • 🎤 Voice • 🎥 Video Notes • 🎞️ Animations\n\
+        📍 Locations • 👤 Contacts • 📊 Polls • 🎲 Dice\n\n\
         *Commands:*\n\
         /encode \\<text\\> \\- Encode text with random emoji\n\
         /encode \\(reply\\) \\- Encode replied message or file\n\
         /decode \\<emoji\\> \\- Decode hidden message or file\n\
-        /decode \\(reply\\) \\- Decode replied message\n\n\
+        /decode \\(reply\\) \\- Decode replied message\n\
+        /inspect \\(reply\\) \\- Explain what a hidden file points to\n\
+        /favorites \\- Pin emojis to the top of the keyboard\n\
+        /cancel \\- Stop waiting for a custom emoji\n\n\
         *In Groups:*\n\
-        Use /encode or /decode commands with text or as reply to messages/files\\.\n\n\
+        Use /encode or /decode commands with text or as reply to messages/files\\.\n\
+        A bare /encode reply shows an emoji keyboard that only works for you, /buttons admins lets admins press it too\\.\n\n\
         *Inline Mode:*\n\
-        Type @EmojiEncoderBot followed by your text in any chat\\.\n\n\
+        Type @EmojiEncoderBot followed by your text in any chat\\.\n\
+        Start with `:fire` or another keyword to pick emojis by name\\.\n\n\
         *Other Commands:*\n\
         /start \\- Start the bot\n\
         /help \\- Show this help message\n\
@@ -165,52 +182,157 @@ pub async fn stats_handler(bot: Bot, msg: Message, db: DbClient, admin_ids: Vec<
     Ok(())
 }
 
-pub fn create_emoji_keyboard() -> InlineKeyboardMarkup {
+/// Emoji keyboard of a user, loading their favourite and recent emojis
+pub async fn user_emoji_keyboard(db: &Option<DbClient>, user_id: i64) -> InlineKeyboardMarkup {
+    create_emoji_keyboard(&load_user_emojis(db, user_id).await, EmojiCategory::Popular, 0)
+}
+
+/// Emoji picker: the user's emojis, category tabs and one page of the selected category
+/// Tabs and arrows send `1p:<category index>:<page>` (see CallbackAction), which edits the keyboard in place
+pub fn create_emoji_keyboard(user_emojis: &UserEmojis, category: EmojiCategory, page: usize) -> InlineKeyboardMarkup {
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
+    let encode_button = |emoji: &str| action_button(emoji.to_string(), CallbackAction::Encode(emoji.to_string()));
+    let pick_button = |label: String, category: EmojiCategory, page: usize| {
+        action_button(label, CallbackAction::Pick { category, page })
+    };
 
-    // Create 5x3 grid of emojis (15 emojis total)
-    let emojis_to_show = std::cmp::min(EMOJI_LIST.len(), 15);
-    for row in 0..3 {
-        let mut button_row = Vec::new();
-        for col in 0..5 {
-            let idx = row * 5 + col;
-            if idx < emojis_to_show {
-                let emoji = EMOJI_LIST[idx];
-                button_row.push(InlineKeyboardButton::callback(
-                    emoji.to_string(),
-                    format!("encode:{}", emoji),
-                ));
-            }
-        }
-        keyboard.push(button_row);
+    // Pinned emojis first, then a row of recently used ones
+    for row in user_emojis.favorites.chunks(KEYBOARD_COLUMNS) {
+        keyboard.push(row.iter().filter_map(|emoji| encode_button(emoji)).collect());
+    }
+    let recent: Vec<_> = user_emojis
+        .recent
+        .iter()
+        .filter(|emoji| !user_emojis.favorites.contains(emoji))
+        .filter_map(|emoji| encode_button(emoji))
+        .collect();
+    if !recent.is_empty() {
+        keyboard.push(recent);
+    }
+
+    // Category tabs, the open one marked
+    keyboard.push(
+        EmojiCategory::ALL
+            .into_iter()
+            .map(|tab| {
+                let label = if tab == category { format!("·{}·", tab.icon()) } else { tab.icon().to_string() };
+                pick_button(label, tab, 0)
+            })
+            .collect::<Option<_>>()
+            .unwrap_or_default(),
+    );
+
+    let emojis = category.emojis();
+    let page_size = KEYBOARD_COLUMNS * KEYBOARD_ROWS;
+    let page_count = emojis.len().div_ceil(page_size).max(1);
+    let page = page.min(page_count - 1);
+
+    for row in emojis.chunks(page_size).nth(page).unwrap_or_default().chunks(KEYBOARD_COLUMNS) {
+        keyboard.push(row.iter().filter_map(|emoji| encode_button(emoji)).collect());
+    }
+
+    if page_count > 1 {
+        keyboard.push(
+            [
+                pick_button("◀️".to_string(), category, (page + page_count - 1) % page_count),
+                action_button(format!("{}/{}", page + 1, page_count), CallbackAction::Noop),
+                pick_button("▶️".to_string(), category, (page + 1) % page_count),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        );
     }
 
     // Add Random button
-    keyboard.push(vec![InlineKeyboardButton::callback(
-        "🎲 Random",
-        "random".to_string(),
-    )]);
+    keyboard.extend(action_button("🎲 Random".to_string(), CallbackAction::Random).map(|button| vec![button]));
 
     // Add Custom button
-    keyboard.push(vec![InlineKeyboardButton::callback(
-        "✏️ Custom Emoji",
-        "custom".to_string(),
-    )]);
+    keyboard.extend(action_button("✏️ Custom Emoji".to_string(), CallbackAction::Custom).map(|button| vec![button]));
 
     InlineKeyboardMarkup::new(keyboard)
 }
 
-pub async fn encode_command_handler(bot: Bot, msg: Message, text: String) -> ResponseResult<()> {
+/// A keyboard button, None if its data would exceed Telegram's limit
+pub fn action_button(label: String, action: CallbackAction) -> Option<InlineKeyboardButton> {
+    match action.to_data() {
+        Ok(data) => Some(InlineKeyboardButton::callback(label, data)),
+        Err(e) => {
+            log::warn!("Skipping button {}: {}", label, e);
+            None
+        }
+    }
+}
+
+pub async fn encode_command_handler(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    media_groups: MediaGroupStorage,
+    db: Option<DbClient>,
+    config: BotConfig,
+    keyboard_owners: KeyboardOwnerStorage,
+) -> ResponseResult<()> {
+    // In groups a bare /encode reply offers the emoji keyboard, only its caller can press it
+    if !msg.chat.is_private()
+        && text.trim().is_empty()
+        && let Some(reply_msg) = msg.reply_to_message()
+        && let Some(user) = msg.from.as_ref()
+    {
+        let keyboard = bot.send_message(msg.chat.id, "Select an emoji to hide the replied message:")
+            .reply_parameters(teloxide::types::ReplyParameters::new(reply_msg.id))
+            .reply_markup(user_emoji_keyboard(&db, user.id.0 as i64).await)
+            .await?;
+        record_keyboard_owner(&keyboard_owners, &db, &keyboard, user).await;
+        return Ok(());
+    }
+
+    // A Premium custom emoji at the start picks the carrier, a random emoji is used otherwise
+    let (carrier, text) = match leading_custom_emoji(&msg, &text) {
+        Some((carrier, rest)) => (carrier, rest.to_string()),
+        None => (Carrier::new(get_random_emoji()), text),
+    };
+
     // Check if replying to a file message
     if text.trim().is_empty() {
         if let Some(reply_msg) = msg.reply_to_message() {
+            // Encode the whole album if the replied file is part of one we collected
+            if let Some(media_group_id) = reply_msg.media_group_id()
+                && let Some(group) = get_media_group(&media_groups, &media_group_id.0).await
+            {
+                match encode_album(&carrier.emoji, &group.file_ids()) {
+                    Ok(encoded) => {
+                        send_encoded(&bot, msg.chat.id, &encoded, &carrier).await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, format!("❌ Error encoding: {}", e))
+                            .await?;
+                    }
+                }
+                return Ok(());
+            }
+
             // Try to extract file info from the replied message
-            if let Some((file_id, _file_type)) = extract_file_info_from_msg(reply_msg) {
+            if let Some(info) = extract_file_info(reply_msg) {
                 // It's a file, encode the file_id
-                let emoji = get_random_emoji();
-                match encode_file_id(emoji, &file_id) {
+                let user_id = msg.from.as_ref().map(|u| u.id.0 as i64).unwrap_or(0);
+                match encode_and_store_file(&bot, &db, &config, user_id, &info, &carrier.emoji).await {
                     Ok(encoded) => {
-                        bot.send_message(msg.chat.id, &encoded).await?;
+                        send_encoded(&bot, msg.chat.id, &encoded, &carrier).await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, format!("❌ Error encoding: {}", e))
+                            .await?;
+                    }
+                }
+                return Ok(());
+            }
+
+            // Locations, contacts, polls and dice
+            if let Some(content) = extract_shared_content(reply_msg) {
+                match encode_content(&carrier.emoji, &content) {
+                    Ok(encoded) => {
+                        send_encoded(&bot, msg.chat.id, &encoded, &carrier).await?;
                     }
                     Err(e) => {
                         bot.send_message(msg.chat.id, format!("❌ Error encoding: {}", e))
@@ -228,10 +350,9 @@ pub async fn encode_command_handler(bot: Bot, msg: Message, text: String) -> Res
                 return Ok(());
             }
 
-            let emoji = get_random_emoji();
-            match encode(emoji, &text_to_encode) {
+            match encode(&carrier.emoji, &text_to_encode) {
                 Ok(encoded) => {
-                    bot.send_message(msg.chat.id, &encoded).await?;
+                    send_encoded(&bot, msg.chat.id, &encoded, &carrier).await?;
                 }
                 Err(e) => {
                     bot.send_message(msg.chat.id, format!("❌ Error encoding: {}", e))
@@ -247,10 +368,9 @@ pub async fn encode_command_handler(bot: Bot, msg: Message, text: String) -> Res
     }
 
     // Text provided directly in command
-    let emoji = get_random_emoji();
-    match encode(emoji, &text) {
+    match encode(&carrier.emoji, &text) {
         Ok(encoded) => {
-            bot.send_message(msg.chat.id, &encoded).await?;
+            send_encoded(&bot, msg.chat.id, &encoded, &carrier).await?;
         }
         Err(e) => {
             bot.send_message(msg.chat.id, format!("❌ Error encoding: {}", e))
@@ -261,18 +381,25 @@ pub async fn encode_command_handler(bot: Bot, msg: Message, text: String) -> Res
     Ok(())
 }
 
-pub async fn decode_command_handler(bot: Bot, msg: Message, text: String) -> ResponseResult<()> {
-    let text_to_decode = if text.trim().is_empty() {
-        // If no text provided, check if it's a reply to a message
-        if let Some(reply_msg) = msg.reply_to_message() {
-            reply_msg.text().unwrap_or("").to_string()
-        } else {
+pub async fn decode_command_handler(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    db: Option<DbClient>,
+    storage_chat_id: Option<ChatId>,
+) -> ResponseResult<()> {
+    let user_id = msg.from.as_ref().map(|u| u.id.0 as i64).unwrap_or(0);
+    let replied_text = msg.reply_to_message().and_then(|reply_msg| reply_msg.text());
+    // Replying with /decode <password> unlocks a password protected message
+    let (text_to_decode, password) = match replied_text {
+        Some(replied) if text.trim().is_empty() => (replied.to_string(), None),
+        Some(replied) if !has_variation_selectors(&text) => (replied.to_string(), Some(text.trim().to_string())),
+        _ if !text.trim().is_empty() => (text, None),
+        _ => {
             bot.send_message(msg.chat.id, "❌ Please provide encoded emoji to decode or reply to a message with /decode")
                 .await?;
             return Ok(());
         }
-    } else {
-        text
     };
 
     if text_to_decode.is_empty() {
@@ -282,29 +409,49 @@ pub async fn decode_command_handler(bot: Bot, msg: Message, text: String) -> Res
     }
 
     // Check if the text contains variation selectors
-    if !text_to_decode.chars().any(|c| {
-        let code = c as u32;
-        (0xFE00..=0xFE0F).contains(&code) || (0xE0100..=0xE01EF).contains(&code)
-    }) {
+    if !has_variation_selectors(&text_to_decode) {
         bot.send_message(msg.chat.id, "❌ No encoded message found")
             .await?;
         return Ok(());
     }
 
-    match decode_with_file_check(&text_to_decode) {
-        Ok((is_file, content)) => {
+    match decode_payload(&text_to_decode) {
+        Ok(DecodedPayload::File(file)) => {
+            // It's a file_id, try to send the file
+            handle_decode_file(&bot, &db, user_id, msg.chat.id, &file, storage_chat_id).await?;
+        }
+        Ok(DecodedPayload::Album(file_ids)) => {
+            handle_decode_album(&bot, &db, user_id, msg.chat.id, &file_ids).await?;
+        }
+        Ok(DecodedPayload::Content(content)) => {
+            handle_decode_content(&bot, msg.chat.id, &content).await?;
+        }
+        Ok(DecodedPayload::Text(content)) => {
             if content.is_empty() {
                 bot.send_message(msg.chat.id, "❌ No encoded message found")
                     .await?;
-            } else if is_file {
-                // It's a file_id, try to send the file
-                handle_decode_file_command(&bot, &msg, &content).await?;
             } else {
                 // It's regular text
                 bot.send_message(msg.chat.id, format!("🔓 Decoded message:\n\n{}", content))
                     .await?;
             }
         }
+        Ok(DecodedPayload::Locked(locked)) => match password {
+            Some(password) => match locked.unlock_blocking(&password).await {
+                Ok(content) => {
+                    bot.send_message(msg.chat.id, format!("🔓 Decoded message:\n\n{}", content))
+                        .await?;
+                }
+                Err(e) => {
+                    bot.send_message(msg.chat.id, format!("❌ {}", e))
+                        .await?;
+                }
+            },
+            None => {
+                bot.send_message(msg.chat.id, LOCKED_MESSAGE_HINT)
+                    .await?;
+            }
+        },
         Err(e) => {
             bot.send_message(msg.chat.id, format!("❌ Error decoding: {}", e))
                 .await?;
@@ -314,99 +461,223 @@ pub async fn decode_command_handler(bot: Bot, msg: Message, text: String) -> Res
     Ok(())
 }
 
-/// Extract file_id and file_type from a message
-fn extract_file_info_from_msg(msg: &Message) -> Option<(String, String)> {
-    if let Some(photo) = msg.photo() {
-        if let Some(largest) = photo.last() {
-            return Some((largest.file.id.to_string(), "photo".to_string()));
-        }
-    }
+/// List, pin and unpin a user's favourite carrier emojis
+pub async fn favorites_command_handler(bot: Bot, msg: Message, text: String, db: Option<DbClient>) -> ResponseResult<()> {
+    let Some(user_id) = msg.from.as_ref().map(|u| u.id.0 as i64) else {
+        return Ok(());
+    };
+    let Some(client) = &db else {
+        bot.send_message(msg.chat.id, "❌ Favorites unavailable (database not connected)").await?;
+        return Ok(());
+    };
 
-    if let Some(video) = msg.video() {
-        return Some((video.file.id.to_string(), "video".to_string()));
-    }
+    let emojis = load_user_emojis(&db, user_id).await;
+    let text = text.trim();
+    let (action, argument) = text
+        .split_once(char::is_whitespace)
+        .map_or((text, ""), |(action, argument)| (action, argument.trim()));
+
+    // Each change is a single update, so concurrent presses can't undo each other
+    let reply = match (action, emoji_argument(argument)) {
+        ("" | "list", _) => Ok(describe_favorites(&emojis)),
+        ("add", Some(emoji)) => client.add_favorite_emoji(user_id, &emoji).await.map(|added| {
+            if added {
+                format!("⭐ Pinned {}", emoji)
+            } else if emojis.favorites.contains(&emoji) {
+                format!("{} is already a favorite", emoji)
+            } else {
+                format!("❌ You can pin at most {} emojis", FAVORITES_LIMIT)
+            }
+        }),
+        ("remove", Some(emoji)) => client.remove_favorite_emoji(user_id, &emoji).await.map(|removed| {
+            if removed {
+                format!("Unpinned {}", emoji)
+            } else {
+                format!("{} is not a favorite", emoji)
+            }
+        }),
+        ("clear", _) => client
+            .clear_favorite_emojis(user_id)
+            .await
+            .map(|()| "Cleared your favorites".to_string()),
+        _ => Ok(FAVORITES_USAGE.to_string()),
+    };
+    let reply = reply.unwrap_or_else(|e| {
+        log::error!("Failed to save favorites of user {}: {}", user_id, e);
+        "❌ Could not save your favorites, please try again".to_string()
+    });
 
-    if let Some(audio) = msg.audio() {
-        return Some((audio.file.id.to_string(), "audio".to_string()));
-    }
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
 
-    if let Some(document) = msg.document() {
-        return Some((document.file.id.to_string(), "document".to_string()));
-    }
+/// Stop waiting for the emoji of a pending custom emoji step
+pub async fn cancel_command_handler(bot: Bot, msg: Message, state_storage: StateStorage) -> ResponseResult<()> {
+    let Some(user_id) = msg.from.as_ref().map(|u| u.id.0 as i64) else {
+        return Ok(());
+    };
 
-    if let Some(sticker) = msg.sticker() {
-        return Some((sticker.file.id.to_string(), "sticker".to_string()));
-    }
+    let reply = cancel_pending_state(&state_storage, &StateKey::new(&msg, user_id)).await;
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
 
-    if let Some(voice) = msg.voice() {
-        return Some((voice.file.id.to_string(), "voice".to_string()));
+/// Clear a user's pending step, returning the reply to /cancel
+async fn cancel_pending_state(state_storage: &StateStorage, key: &StateKey) -> String {
+    match clear_user_state(state_storage, key).await {
+        Some(state) => format!("✅ Cancelled, I'm no longer waiting for an emoji to hide your {}.", state.subject()),
+        None => "Nothing to cancel.".to_string(),
     }
+}
 
-    if let Some(video_note) = msg.video_note() {
-        return Some((video_note.file.id.to_string(), "video note".to_string()));
+/// Let a group's admins choose whether they may press keyboards sent to other members
+pub async fn buttons_command_handler(bot: Bot, msg: Message, text: String, db: Option<DbClient>) -> ResponseResult<()> {
+    if matches!(msg.chat.kind, teloxide::types::ChatKind::Private(_)) {
+        bot.send_message(msg.chat.id, "This setting is for groups, your keyboards here are only yours.").await?;
+        return Ok(());
     }
+    let Some(user) = msg.from.as_ref() else {
+        return Ok(());
+    };
+    let Some(client) = &db else {
+        bot.send_message(msg.chat.id, "❌ Settings unavailable (database not connected)").await?;
+        return Ok(());
+    };
 
-    if let Some(animation) = msg.animation() {
-        return Some((animation.file.id.to_string(), "animation".to_string()));
+    let mut settings = load_chat_settings(&db, msg.chat.id.0).await;
+    let admins_can_press = match text.trim() {
+        "" => {
+            let current = if settings.admins_can_press { "their owner and admins" } else { "only their owner" };
+            bot.send_message(msg.chat.id, format!("Emoji keyboards can be pressed by {}.\n\n{}", current, BUTTONS_USAGE))
+                .await?;
+            return Ok(());
+        }
+        "admins" => true,
+        "owner" => false,
+        _ => {
+            bot.send_message(msg.chat.id, BUTTONS_USAGE).await?;
+            return Ok(());
+        }
+    };
+
+    if !is_chat_admin(&bot, msg.chat.id, user.id).await {
+        bot.send_message(msg.chat.id, "❌ Only group admins can change this.").await?;
+        return Ok(());
     }
 
-    None
+    settings.admins_can_press = admins_can_press;
+    let reply = match save_chat_settings(client, msg.chat.id.0, &settings).await {
+        Ok(()) if admins_can_press => "✅ Admins can now press emoji keyboards sent to other members.",
+        Ok(()) => "✅ Emoji keyboards can now only be pressed by the member they were sent to.",
+        Err(e) => {
+            log::error!("Failed to save settings of chat {}: {}", msg.chat.id, e);
+            "❌ Could not save the setting, please try again"
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
 }
 
-/// Handle decoding and sending files in command context
-async fn handle_decode_file_command(bot: &Bot, msg: &Message, file_id: &str) -> ResponseResult<()> {
-    let send_result = try_send_file_command(bot, msg.chat.id, file_id).await;
-
-    match send_result {
-        Ok(_) => Ok(()),
-        Err(_) => {
-            bot.send_message(
-                msg.chat.id,
-                format!("🔓 Decoded file ID:\n\n`{}`\n\n⚠️ Unable to send this file. It may have been deleted or is no longer accessible.", file_id)
-            )
-            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-            .await?;
-            Ok(())
-        }
-    }
+const BUTTONS_USAGE: &str = "/buttons admins - admins may press keyboards sent to other members\n\
+    /buttons owner - only the member a keyboard was sent to may press it";
+
+const FAVORITES_USAGE: &str = "/favorites add <emoji or name> - pin an emoji\n\
+    /favorites remove <emoji or name> - unpin it\n\
+    /favorites clear - unpin all";
+
+/// The emoji a /favorites argument names, typed or by name like fire
+fn emoji_argument(argument: &str) -> Option<String> {
+    split_leading_emoji(argument)
+        .map(|(emoji, _)| emoji)
+        .or_else(|| find_emoji(argument))
+        .map(str::to_string)
 }
 
-/// Try to send a file by its file_id (for commands)
-/// Decodes the file_id to determine the type and sends it directly
-async fn try_send_file_command(bot: &Bot, chat_id: ChatId, file_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Decode the file_id to get the file type
-    let file_type = decode_file_type(file_id)?;
+fn describe_favorites(emojis: &UserEmojis) -> String {
+    let list = |emojis: &[String]| if emojis.is_empty() { "none".to_string() } else { emojis.join(" ") };
 
-    // Send based on the decoded type
-    match file_type {
-        FileType::Photo => {
-            bot.send_photo(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Video => {
-            bot.send_video(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Voice => {
-            bot.send_voice(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Document => {
-            bot.send_document(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Sticker => {
-            bot.send_sticker(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Audio => {
-            bot.send_audio(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Animation => {
-            bot.send_animation(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::VideoNote => {
-            bot.send_video_note(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Unknown => {
-            return Err("Unknown file type".into());
+    format!(
+        "⭐ Favorites: {}\n🕘 Recent: {}\n\n{}",
+        list(&emojis.favorites),
+        list(&emojis.recent),
+        FAVORITES_USAGE
+    )
+}
+
+pub async fn inspect_command_handler(bot: Bot, msg: Message, text: String) -> ResponseResult<()> {
+    // Only a replied file carries a file_unique_id
+    let mut unique_id_report = None;
+
+    let file_ids = if text.trim().is_empty() {
+        match msg.reply_to_message() {
+            Some(reply_msg) => match extract_file_info(reply_msg) {
+                Some(info) => {
+                    unique_id_report = Some(describe_file_unique_id(&info.file_unique_id));
+                    vec![info.file_id]
+                }
+                None => file_ids_from_text(reply_msg.text().unwrap_or("")),
+            },
+            None => {
+                bot.send_message(msg.chat.id, "❌ Please provide a file_id or encoded emoji, or reply to a file with /inspect")
+                    .await?;
+                return Ok(());
+            }
         }
+    } else {
+        file_ids_from_text(&text)
+    };
+
+    if file_ids.is_empty() {
+        bot.send_message(msg.chat.id, "❌ No file found to inspect")
+            .await?;
+        return Ok(());
     }
 
+    let mut reports: Vec<String> = file_ids
+        .iter()
+        .map(|file_id| {
+            let mut report = format!("🔍 {}\n\n{}", file_id, describe_file_id(file_id));
+            let canonical = canonical_file_id(file_id);
+            if canonical != *file_id {
+                report.push_str(&format!("\n\n🛠 Repaired file ID:\n{}", canonical));
+            }
+            report
+        })
+        .collect();
+    reports.extend(unique_id_report);
+
+    bot.send_message(msg.chat.id, reports.join("\n\n"))
+        .await?;
+
     Ok(())
 }
+
+/// Get the file_ids hidden in an encoded emoji, or treat the text as a raw file_id
+fn file_ids_from_text(text: &str) -> Vec<String> {
+    match decode_payload(text) {
+        Ok(DecodedPayload::File(file)) => vec![file.file_id],
+        Ok(DecodedPayload::Album(file_ids)) => file_ids,
+        _ => text.split_whitespace().next().map(|id| vec![id.to_string()]).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::models::{BotState, get_user_state, set_user_state};
+    use crate::models::state_store::MemoryStateStore;
+
+    #[tokio::test]
+    async fn test_cancel_clears_state() {
+        let storage: StateStorage = Arc::new(MemoryStateStore::default());
+        let key = StateKey::private(7);
+        set_user_state(&storage, &key, BotState::AwaitingCustomEmoji { text: "secret".to_string() }).await;
+
+        let reply = cancel_pending_state(&storage, &key).await;
+        assert!(reply.contains("your text"), "{}", reply);
+        assert!(matches!(get_user_state(&storage, &key).await, BotState::Idle));
+        assert_eq!(cancel_pending_state(&storage, &key).await, "Nothing to cancel.");
+    }
+}
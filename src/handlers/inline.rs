@@ -1,13 +1,34 @@
 use teloxide::{
     prelude::*,
     types::{
-        InlineQueryResult, InlineQueryResultArticle, InputMessageContent,
-        InputMessageContentText,
+        ChosenInlineResult, FileId, InlineQueryResult, InlineQueryResultArticle, InlineQueryResultCachedAudio,
+        InlineQueryResultCachedDocument, InlineQueryResultCachedMpeg4Gif, InlineQueryResultCachedPhoto,
+        InlineQueryResultCachedSticker, InlineQueryResultCachedVideo, InlineQueryResultCachedVoice,
+        InlineQueryResultContact, InlineQueryResultLocation, InlineQueryResultVenue, InputMessageContent,
+        InputMessageContentText, InlineKeyboardMarkup,
     },
 };
-use crate::utils::{encode, get_random_emoji, EMOJI_LIST};
+use crate::models::{DbClient, InlinePayloadStorage, Whisper, load_user_emojis, persist_inline_payload, stage_inline_payload, stage_whisper};
+use crate::handlers::commands::action_button;
+use crate::utils::{
+    CallbackAction, decode_file_type, decode_payload, get_random_emoji, has_variation_selectors, search_emojis,
+    split_leading_emoji, split_leading_keyword, split_options, DecodedPayload, EncoderError,
+    FileType, OptionError, SendMethod, SharedContent, TextPayload, emoji_catalog, OPTIONS_HELP,
+};
+
+// Length of the decoded text shown in a result description
+const PREVIEW_LENGTH: usize = 100;
+// Telegram shows at most 50 results per page
+const INLINE_PAGE_SIZE: usize = 50;
+// Seconds Telegram may cache encoding results
+const INLINE_CACHE_TIME: u32 = 300;
 
-pub async fn inline_query_handler(bot: Bot, q: InlineQuery) -> ResponseResult<()> {
+pub async fn inline_query_handler(
+    bot: Bot,
+    q: InlineQuery,
+    payloads: InlinePayloadStorage,
+    db: Option<DbClient>,
+) -> ResponseResult<()> {
     let query = q.query.trim();
 
     if query.is_empty() {
@@ -15,83 +36,175 @@ pub async fn inline_query_handler(bot: Bot, q: InlineQuery) -> ResponseResult<()
         return Ok(());
     }
 
-    // Parse query: first character(s) might be emoji, rest is text
-    // Format: <emoji> <text> or just <text> (use default emoji)
-    let (emoji, text) = parse_inline_query(query);
+    // A pasted encoded emoji is revealed instead of encoded again
+    if let Some(results) = decoded_results(query) {
+        bot.answer_inline_query(q.id.clone(), results).await?;
+        return Ok(());
+    }
+
+    // @user1 @user2 secret: only the mentioned users can reveal it
+    if let Some(whisper) = Whisper::parse(q.from.id.0 as i64, query) {
+        let key = stage_whisper(&payloads, &whisper).await;
+        bot.answer_inline_query(q.id.clone(), vec![whisper_result(&whisper, &key)])
+            // Every user must get their own whisper, not a cached one
+            .is_personal(true)
+            .cache_time(0)
+            .await?;
+        return Ok(());
+    }
+
+    let favorites = load_user_emojis(&db, q.from.id.0 as i64).await.favorites;
+
+    // `:fire secret` offers the emojis matching a keyword instead of the whole catalog
+    let keyword = split_leading_keyword(query);
+    let (emoji, text, candidates) = match keyword {
+        Some((keyword, text)) => {
+            let matches = search_emojis(keyword);
+            if matches.is_empty() {
+                bot.answer_inline_query(q.id.clone(), vec![no_emoji_found_result(keyword)]).await?;
+                return Ok(());
+            }
+            (String::new(), text.to_string(), matches)
+        }
+        None => {
+            // Parse query: first character(s) might be emoji, rest is text
+            // Format: <emoji> <text> or just <text> (use default emoji)
+            let (emoji, text) = parse_inline_query(query);
+            // The user's favourites come first
+            let mut catalog: Vec<&str> = favorites.iter().map(String::as_str).collect();
+            for catalog_emoji in emoji_catalog() {
+                if !favorites.iter().any(|favorite| favorite == catalog_emoji) {
+                    catalog.push(catalog_emoji);
+                }
+            }
+            (emoji, text, catalog)
+        }
+    };
+
+    // pw:, ttl:, z: and s: flags come before the text
+    let (options, text) = match split_options(&text) {
+        Ok((options, text)) => (options, text.to_string()),
+        Err(e) => {
+            bot.answer_inline_query(q.id.clone(), vec![options_help_result(&e)]).await?;
+            return Ok(());
+        }
+    };
+    let payload = match TextPayload::new_blocking(&text, &options).await {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("Failed to build inline payload: {}", e);
+            return Ok(());
+        }
+    };
+    let summary = options.summary();
+    // A password or an expiry protects the message itself
+    let protected = options.password.is_some() || options.ttl.is_some();
+
+    // Recipients can reveal the secret with a button instead of forwarding it to the bot
+    // Protected messages get none, it would bypass the protection
+    let reveal_key = if text.is_empty() || protected {
+        None
+    } else {
+        Some(stage_inline_payload(&payloads, &text).await)
+    };
+    let reveal_markup = reveal_key.as_deref().map(reveal_keyboard);
 
+    let selected = (!emoji.is_empty() && emoji != "😀").then_some(emoji.as_str());
+    // The offset is the position in the emoji catalog the page starts at
+    let start: usize = q.offset.parse().unwrap_or(0);
     let mut results: Vec<InlineQueryResult> = Vec::new();
 
-    // If emoji was specified, add it FIRST
-    if !emoji.is_empty() && emoji != "😀" {
-        if let Ok(encoded) = encode(&emoji, &text) {
+    if start == 0 {
+        // If emoji was specified, add it FIRST
+        if let Some(selected) = selected
+            && let Ok(encoded) = payload.encode(selected)
+        {
             results.push(create_inline_result(
-                format!("selected_{}", emoji),
-                &emoji,
+                // A fixed name, the emoji itself could exceed the 64 byte limit of result ids
+                result_id("selected".to_string(), reveal_key.as_deref()),
+                selected,
                 &text,
                 &encoded,
+                &summary,
+                reveal_markup.clone(),
             ));
         }
-    }
 
-    // Add random option at the top (after selected emoji)
-    let random_emoji = get_random_emoji();
-    if let Ok(encoded) = encode(random_emoji, &text) {
-        results.push(create_inline_result("random".to_string(), random_emoji, &text, &encoded));
+        // Add random option at the top (after selected emoji)
+        let random_emoji = get_random_emoji();
+        if keyword.is_none()
+            && let Ok(encoded) = payload.encode(random_emoji)
+        {
+            results.push(create_inline_result(
+                result_id("random".to_string(), reveal_key.as_deref()),
+                random_emoji,
+                &text,
+                &encoded,
+                &summary,
+                reveal_markup.clone(),
+            ));
+        }
     }
 
-    // Create results for each predefined emoji
-    for (idx, &preset_emoji) in EMOJI_LIST.iter().enumerate() {
+    // Fill the rest of the page from the catalog or the keyword matches
+    let page_size = INLINE_PAGE_SIZE - results.len();
+    let mut catalog = candidates.into_iter().enumerate().skip(start).peekable();
+    for (idx, catalog_emoji) in catalog.by_ref().take(page_size) {
         // Skip if it's the same as the selected emoji
-        if !emoji.is_empty() && emoji != "😀" && preset_emoji == emoji {
+        if selected == Some(catalog_emoji) {
             continue;
         }
 
-        if let Ok(encoded) = encode(preset_emoji, &text) {
+        if let Ok(encoded) = payload.encode(catalog_emoji) {
             results.push(create_inline_result(
-                format!("emoji_{}", idx),
-                preset_emoji,
+                result_id(format!("emoji_{}", idx), reveal_key.as_deref()),
+                catalog_emoji,
                 &text,
                 &encoded,
+                &summary,
+                reveal_markup.clone(),
             ));
         }
-
-        // Limit to 50 results (Telegram's limit)
-        if results.len() >= 50 {
-            break;
-        }
     }
+    let next_offset = catalog.peek().map(|(idx, _)| idx.to_string()).unwrap_or_default();
 
-    bot.answer_inline_query(q.id.clone(), results).await?;
+    bot.answer_inline_query(q.id.clone(), results)
+        .next_offset(next_offset)
+        // Results depend on the query and the user's favourites, apart from the random pick
+        // An expiry counts from encoding, so those must not come from the cache
+        .cache_time(if protected { 0 } else { INLINE_CACHE_TIME })
+        .is_personal(true)
+        .await?;
     Ok(())
 }
 
-fn parse_inline_query(query: &str) -> (String, String) {
-    let chars: Vec<char> = query.chars().collect();
-
-    if chars.is_empty() {
-        return ("😀".to_string(), String::new());
+/// Persist the secret behind a picked result, staged when the query was answered
+/// Telegram only reports picks with inline feedback enabled in @BotFather
+pub async fn chosen_inline_result_handler(
+    result: ChosenInlineResult,
+    payloads: InlinePayloadStorage,
+    db: Option<DbClient>,
+) -> ResponseResult<()> {
+    if let Some((_, key)) = result.result_id.rsplit_once(':') {
+        persist_inline_payload(&payloads, &db, key).await;
     }
+    Ok(())
+}
 
-    // Check if first character is an emoji
-    let first_char = chars[0];
-    if is_emoji_char(first_char) {
-        let emoji = chars[0].to_string();
-        let text = if chars.len() > 1 {
-            chars[1..].iter().collect::<String>().trim().to_string()
-        } else {
-            String::new()
-        };
-        return (emoji, text);
+/// Result ids carry the key of the secret, so a picked result names the one to keep
+fn result_id(name: String, key: Option<&str>) -> String {
+    match key {
+        Some(key) => format!("{}:{}", name, key),
+        None => name,
     }
-
-    ("😀".to_string(), query.to_string())
 }
 
-fn is_emoji_char(c: char) -> bool {
-    let code = c as u32;
-    (0x1F300..=0x1F9FF).contains(&code) ||
-    (0x2600..=0x27BF).contains(&code) ||
-    (0x1F600..=0x1F64F).contains(&code)
+fn parse_inline_query(query: &str) -> (String, String) {
+    // The leading emoji is kept whole, so flags, skin tones and ZWJ sequences work as carriers
+    match split_leading_emoji(query) {
+        Some((emoji, text)) => (emoji.to_string(), text.trim_end().to_string()),
+        None => ("😀".to_string(), query.to_string()),
+    }
 }
 
 fn create_inline_result(
@@ -99,11 +212,216 @@ fn create_inline_result(
     emoji: &str,
     text: &str,
     encoded: &str,
+    options_summary: &str,
+    reveal_markup: Option<InlineKeyboardMarkup>,
 ) -> InlineQueryResult {
-    InlineQueryResult::Article(InlineQueryResultArticle::new(
+    let description = if options_summary.is_empty() {
+        format!("Encode with {}", emoji)
+    } else {
+        format!("Encode with {} · {}", emoji, options_summary)
+    };
+
+    let mut result = InlineQueryResultArticle::new(
         id,
         format!("{} {}", emoji, text),
         InputMessageContent::Text(InputMessageContentText::new(encoded)),
     )
-    .description(format!("Encode with {}", emoji)))
+    .description(description);
+
+    if let Some(markup) = reveal_markup {
+        result = result.reply_markup(markup);
+    }
+
+    InlineQueryResult::Article(result)
+}
+
+/// An article explaining why a pasted emoji can't be revealed inline
+fn notice_result(id: &str, title: &str, description: &str) -> InlineQueryResult {
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::new(
+            id,
+            title,
+            InputMessageContent::Text(InputMessageContentText::new(format!("{}\n{}", title, description))),
+        )
+        .description(description),
+    )
+}
+
+/// Shown instead of encoding when the option flags are malformed
+fn options_help_result(error: &OptionError) -> InlineQueryResult {
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::new(
+            "options_help",
+            format!("⚙️ {}", error),
+            InputMessageContent::Text(InputMessageContentText::new(format!(
+                "⚙️ {}\n\nOptions go before the text, e.g. 🔥 pw:hunter2 ttl:1d your text\n\n{}",
+                error, OPTIONS_HELP
+            ))),
+        )
+        .description(OPTIONS_HELP.replace('\n', " · ")),
+    )
+}
+
+/// Shown instead of encoding when a `:keyword` matches no emoji
+fn no_emoji_found_result(keyword: &str) -> InlineQueryResult {
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::new(
+            "no_emoji",
+            format!("No emoji found for :{}", keyword),
+            InputMessageContent::Text(InputMessageContentText::new(format!("No emoji found for :{}", keyword))),
+        )
+        .description("Try another word, e.g. :fire or :pizza"),
+    )
+}
+
+/// The whisper message only names its recipients, the secret stays with the bot
+fn whisper_result(whisper: &Whisper, key: &str) -> InlineQueryResult {
+    let recipients = whisper.recipients();
+
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::new(
+            result_id("whisper".to_string(), Some(key)),
+            format!("🤫 Whisper to {}", recipients),
+            InputMessageContent::Text(InputMessageContentText::new(format!(
+                "🤫 A whisper for {}",
+                recipients
+            ))),
+        )
+        .description("Only they can reveal it")
+        .reply_markup(reveal_button(CallbackAction::Whisper(key.to_string()))),
+    )
+}
+
+fn reveal_keyboard(key: &str) -> InlineKeyboardMarkup {
+    reveal_button(CallbackAction::Reveal(key.to_string()))
+}
+
+fn reveal_button(action: CallbackAction) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(action_button("🔓 Reveal".to_string(), action).map(|button| vec![button]))
+}
+
+/// Results revealing what is hidden in an encoded emoji
+/// Returns None if the query does not carry hidden data
+fn decoded_results(query: &str) -> Option<Vec<InlineQueryResult>> {
+    if !has_variation_selectors(query) {
+        return None;
+    }
+
+    let payload = match decode_payload(query) {
+        Ok(payload) => payload,
+        Err(EncoderError::Expired(expired_at)) => {
+            return Some(vec![notice_result("expired", "⌛ This message has expired", &format!("It expired on {}", expired_at))]);
+        }
+        Err(_) => return None,
+    };
+
+    let results = match payload {
+        // A lone emoji presentation selector (as in ❤️) decodes to a control character
+        DecodedPayload::Text(text) if text.chars().any(|c| !c.is_control()) => {
+            vec![decoded_text_result(&text)]
+        }
+        DecodedPayload::Text(_) => return None,
+        DecodedPayload::File(file) => {
+            vec![cached_file_result("decoded_file".to_string(), &file.file_id, file.send_method)]
+        }
+        DecodedPayload::Album(file_ids) => file_ids
+            .iter()
+            .enumerate()
+            .map(|(idx, file_id)| cached_file_result(format!("decoded_file_{}", idx), file_id, None))
+            .collect(),
+        DecodedPayload::Content(content) => vec![content_result(&content)],
+        DecodedPayload::Locked(_) => vec![notice_result(
+            "locked",
+            "🔒 This message is password protected",
+            "Send it to the bot and reply to it with /decode <password>",
+        )],
+    };
+
+    Some(results)
+}
+
+fn decoded_text_result(text: &str) -> InlineQueryResult {
+    let mut preview: String = text.chars().take(PREVIEW_LENGTH).collect();
+    if preview.len() < text.len() {
+        preview.push('…');
+    }
+
+    InlineQueryResult::Article(InlineQueryResultArticle::new(
+        "decoded_text",
+        "🔓 Reveal hidden message",
+        InputMessageContent::Text(InputMessageContentText::new(text)),
+    )
+    .description(preview))
+}
+
+/// Cached media result sending a decoded file, using the type recorded in the
+/// payload or else the one in the file_id
+fn cached_file_result(id: String, file_id: &str, recorded: Option<SendMethod>) -> InlineQueryResult {
+    let method = recorded.or_else(|| decode_file_type(file_id).ok().and_then(FileType::send_method));
+    let file = FileId(file_id.to_string());
+
+    match method {
+        Some(SendMethod::Photo) => InlineQueryResult::CachedPhoto(
+            InlineQueryResultCachedPhoto::new(id, file).title("🔓 Hidden photo"),
+        ),
+        Some(SendMethod::Video) => InlineQueryResult::CachedVideo(
+            InlineQueryResultCachedVideo::new(id, file, "🔓 Hidden video"),
+        ),
+        Some(SendMethod::Voice) => InlineQueryResult::CachedVoice(
+            InlineQueryResultCachedVoice::new(id, file, "🔓 Hidden voice message"),
+        ),
+        Some(SendMethod::Document) => InlineQueryResult::CachedDocument(
+            InlineQueryResultCachedDocument::new(id, "🔓 Hidden document", file),
+        ),
+        Some(SendMethod::Sticker) => InlineQueryResult::CachedSticker(InlineQueryResultCachedSticker::new(id, file)),
+        Some(SendMethod::Audio) => InlineQueryResult::CachedAudio(InlineQueryResultCachedAudio::new(id, file)),
+        Some(SendMethod::Animation) => InlineQueryResult::CachedMpeg4Gif(
+            InlineQueryResultCachedMpeg4Gif::new(id, file).title("🔓 Hidden animation"),
+        ),
+        // Video notes have no inline result type
+        Some(SendMethod::VideoNote) | None => InlineQueryResult::Article(
+            InlineQueryResultArticle::new(
+                id,
+                "🔓 Hidden file",
+                InputMessageContent::Text(InputMessageContentText::new(format!("🔓 Decoded file ID:\n\n{}", file_id))),
+            )
+            .description("This file can't be sent inline, send the emoji to the bot instead"),
+        ),
+    }
+}
+
+fn content_result(content: &SharedContent) -> InlineQueryResult {
+    let id = format!("decoded_{}", content.type_name());
+
+    match content {
+        SharedContent::Location { latitude, longitude } => InlineQueryResult::Location(
+            InlineQueryResultLocation::new(id, "📍 Hidden location", *latitude, *longitude),
+        ),
+        SharedContent::Venue { latitude, longitude, title, address, .. } => InlineQueryResult::Venue(
+            InlineQueryResultVenue::new(id, *latitude, *longitude, title, address),
+        ),
+        SharedContent::Contact { phone_number, first_name, last_name, vcard } => {
+            let mut result = InlineQueryResultContact::new(id, phone_number, first_name);
+            if let Some(last_name) = last_name {
+                result = result.last_name(last_name);
+            }
+            if let Some(vcard) = vcard {
+                result = result.vcard(vcard);
+            }
+            InlineQueryResult::Contact(result)
+        }
+        // Polls and dice cannot be sent inline, show them as text
+        SharedContent::Poll { question, options, .. } => {
+            let text = format!("📊 {}\n\n{}", question, options.iter().map(|o| format!("• {}", o)).collect::<Vec<_>>().join("\n"));
+            InlineQueryResult::Article(
+                InlineQueryResultArticle::new(id, "🔓 Hidden poll", InputMessageContent::Text(InputMessageContentText::new(text)))
+                    .description(question),
+            )
+        }
+        SharedContent::Dice { emoji } => InlineQueryResult::Article(InlineQueryResultArticle::new(
+            id,
+            format!("🔓 Hidden {} dice", emoji),
+            InputMessageContent::Text(InputMessageContentText::new(emoji)),
+        )),
+    }
 }
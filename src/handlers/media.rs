@@ -0,0 +1,559 @@
+use teloxide::prelude::*;
+use teloxide::types::{
+    DiceEmoji, FileId, InputFile, MessageId, InputMedia, InputMediaAudio, InputMediaDocument,
+    InputMediaPhoto, InputMediaVideo, InputPollOption, StickerFormat,
+};
+use teloxide::utils::markdown::{code_inline, escape};
+use crate::models::{BotConfig, DbClient};
+use crate::models::db::{EncodedMediaRecord, MediaCopies};
+use crate::utils::encoder::EncoderError;
+use crate::utils::{decode_file_type, encode_file, file_unique_id_of, parse_file_id, parse_file_unique_id, EncodedFile, FileType, MessageOrigin, SendMethod, SharedContent};
+
+/// A file found in a message
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub file_id: String,
+    /// Stable across bots, used to recognise files the user hid before
+    pub file_unique_id: String,
+    pub file_type: FileType,
+    /// Message the file was found in, only ever stored server-side
+    pub origin: Option<MessageOrigin>,
+}
+
+impl FileInfo {
+    fn new(msg: &Message, file: &teloxide::types::FileMeta, file_type: FileType) -> Self {
+        Self {
+            file_id: file.id.to_string(),
+            file_unique_id: file.unique_id.to_string(),
+            file_type,
+            origin: Some(MessageOrigin {
+                chat_id: msg.chat.id.0,
+                message_id: msg.id.0,
+            }),
+        }
+    }
+
+    /// What gets hidden in the emoji: the file_id and how it was sent
+    pub fn to_encoded_file(&self) -> EncodedFile {
+        EncodedFile {
+            file_id: self.file_id.clone(),
+            send_method: self.file_type.send_method(),
+        }
+    }
+}
+
+/// Extract the file from a message
+/// Returns None if the message contains no supported file
+pub fn extract_file_info(msg: &Message) -> Option<FileInfo> {
+    if let Some(photo) = msg.photo()
+        // Get the largest photo size
+        && let Some(largest) = photo.last()
+    {
+        return Some(FileInfo::new(msg, &largest.file, FileType::Photo));
+    }
+
+    if let Some(video) = msg.video() {
+        return Some(FileInfo::new(msg, &video.file, FileType::Video));
+    }
+
+    if let Some(audio) = msg.audio() {
+        return Some(FileInfo::new(msg, &audio.file, FileType::Audio));
+    }
+
+    if let Some(document) = msg.document() {
+        return Some(FileInfo::new(msg, &document.file, FileType::Document));
+    }
+
+    if let Some(sticker) = msg.sticker() {
+        let file_type = match sticker.format() {
+            StickerFormat::Static => FileType::Sticker,
+            StickerFormat::Animated => FileType::AnimatedSticker,
+            StickerFormat::Video => FileType::VideoSticker,
+        };
+        return Some(FileInfo::new(msg, &sticker.file, file_type));
+    }
+
+    if let Some(voice) = msg.voice() {
+        return Some(FileInfo::new(msg, &voice.file, FileType::Voice));
+    }
+
+    if let Some(video_note) = msg.video_note() {
+        return Some(FileInfo::new(msg, &video_note.file, FileType::VideoNote));
+    }
+
+    if let Some(animation) = msg.animation() {
+        return Some(FileInfo::new(msg, &animation.file, FileType::Animation));
+    }
+
+    None
+}
+
+/// Extract non-file content (location, venue, contact, poll, dice) from a message
+pub fn extract_shared_content(msg: &Message) -> Option<SharedContent> {
+    // A venue message also carries its location, so check it first
+    if let Some(venue) = msg.venue() {
+        return Some(SharedContent::Venue {
+            latitude: venue.location.latitude,
+            longitude: venue.location.longitude,
+            title: venue.title.clone(),
+            address: venue.address.clone(),
+            foursquare_id: venue.foursquare_id.clone(),
+            google_place_id: venue.google_place_id.clone(),
+        });
+    }
+
+    if let Some(location) = msg.location() {
+        return Some(SharedContent::Location {
+            latitude: location.latitude,
+            longitude: location.longitude,
+        });
+    }
+
+    if let Some(contact) = msg.contact() {
+        return Some(SharedContent::Contact {
+            phone_number: contact.phone_number.clone(),
+            first_name: contact.first_name.clone(),
+            last_name: contact.last_name.clone(),
+            vcard: contact.vcard.clone(),
+        });
+    }
+
+    if let Some(poll) = msg.poll() {
+        return Some(SharedContent::Poll {
+            question: poll.question.clone(),
+            options: poll.options.iter().map(|option| option.text.clone()).collect(),
+            is_anonymous: poll.is_anonymous,
+            allows_multiple_answers: poll.allows_multiple_answers,
+        });
+    }
+
+    if let Some(dice) = msg.dice() {
+        // DiceEmoji serializes to the emoji itself
+        let emoji = serde_json::to_value(dice.emoji).ok()?.as_str()?.to_string();
+        return Some(SharedContent::Dice { emoji });
+    }
+
+    None
+}
+
+/// Tell the user if they already hid this file before, e.g.
+/// "♻️ This is the same photo you already hid in 🔥 last week."
+pub async fn previous_encoding_note(db: &Option<DbClient>, user_id: i64, info: &FileInfo) -> Option<String> {
+    let db = db.as_ref()?;
+    if info.file_unique_id.is_empty() {
+        return None;
+    }
+
+    match db.find_encoded_media(user_id, &info.file_unique_id).await {
+        Ok(Some(record)) => Some(format!(
+            "♻️ This is the same {} you already hid in {} {}.",
+            info.file_type.name(),
+            record.emoji,
+            format_age(record.last_encoded.timestamp_millis()),
+        )),
+        Ok(None) => None,
+        Err(e) => {
+            log::error!("Failed to look up encoded media: {}", e);
+            None
+        }
+    }
+}
+
+/// Hide a file in an emoji
+/// With a storage channel configured, the file is copied there first (or the
+/// copy from an earlier encoding is reused).
+/// The file, that copy and the message it came from are remembered, keyed by
+/// its file_unique_id, so decoding never trusts message ids from the payload
+pub async fn encode_and_store_file(
+    bot: &Bot,
+    db: &Option<DbClient>,
+    config: &BotConfig,
+    user_id: i64,
+    info: &FileInfo,
+    emoji: &str,
+) -> Result<String, EncoderError> {
+    let encoded = encode_file(emoji, &info.to_encoded_file())?;
+
+    let mut storage_message_id = None;
+    if let Some(storage_chat_id) = config.storage_chat_id {
+        storage_message_id = match stored_copy(db, storage_chat_id, user_id, info).await {
+            Some(message_id) => Some(message_id),
+            None => copy_to_storage(bot, storage_chat_id, info).await,
+        };
+    }
+
+    if let Some(db) = db
+        && !info.file_unique_id.is_empty()
+    {
+        let copies = MediaCopies {
+            storage: config.storage_chat_id.zip(storage_message_id).map(|(chat_id, message_id)| (chat_id.0, message_id)),
+            origin: info.origin,
+        };
+        if let Err(e) = db
+            .record_encoded_media(user_id, &info.file_unique_id, &info.file_id, info.file_type.name(), emoji, copies)
+            .await
+        {
+            log::error!("Failed to record encoded media: {}", e);
+        }
+    }
+
+    Ok(encoded)
+}
+
+/// Storage channel copy made when the user hid this file before
+async fn stored_copy(db: &Option<DbClient>, storage_chat_id: ChatId, user_id: i64, info: &FileInfo) -> Option<i32> {
+    let db = db.as_ref()?;
+    if info.file_unique_id.is_empty() {
+        return None;
+    }
+
+    match db.find_encoded_media(user_id, &info.file_unique_id).await {
+        Ok(record) => record?.storage_message_in(storage_chat_id.0),
+        Err(e) => {
+            log::error!("Failed to look up encoded media: {}", e);
+            None
+        }
+    }
+}
+
+/// Copy the message a file came from to the storage channel
+async fn copy_to_storage(bot: &Bot, storage_chat_id: ChatId, info: &FileInfo) -> Option<i32> {
+    let origin = info.origin?;
+
+    match bot
+        .copy_message(storage_chat_id, ChatId(origin.chat_id), MessageId(origin.message_id))
+        .await
+    {
+        Ok(message_id) => Some(message_id.0),
+        Err(e) => {
+            log::warn!("Failed to copy {} to the storage channel: {}", info.file_type.name(), e);
+            None
+        }
+    }
+}
+
+/// Describe how long ago a timestamp (in milliseconds) was
+fn format_age(timestamp_millis: i64) -> String {
+    let Some(then) = chrono::DateTime::from_timestamp_millis(timestamp_millis) else {
+        return "before".to_string();
+    };
+
+    match (chrono::Utc::now() - then).num_days() {
+        ..=0 => "today".to_string(),
+        1 => "yesterday".to_string(),
+        days @ 2..=6 => format!("{} days ago", days),
+        7..=13 => "last week".to_string(),
+        days @ 14..=59 => format!("{} weeks ago", days / 7),
+        _ => format!("on {}", then.format("%Y-%m-%d")),
+    }
+}
+
+/// Explain what a file_unique_id identifies
+pub fn describe_file_unique_id(file_unique_id: &str) -> String {
+    match parse_file_unique_id(file_unique_id) {
+        Ok(parsed) => format!("Unique ID: {} ({})", file_unique_id, parsed.describe()),
+        Err(e) => format!("Unreadable unique ID {}: {}", file_unique_id, e),
+    }
+}
+
+/// The record the requesting user left when hiding a file, found by the
+/// file_unique_id of the decoded file_id
+/// Only the user's own records are used, so a payload can never point the bot
+/// at somebody else's messages
+async fn own_encoded_media(db: &Option<DbClient>, user_id: i64, file_id: &str) -> Option<EncodedMediaRecord> {
+    let db = db.as_ref()?;
+    let file_unique_id = file_unique_id_of(file_id)?;
+
+    match db.find_encoded_media(user_id, &file_unique_id).await {
+        Ok(record) => record,
+        Err(e) => {
+            log::error!("Failed to look up encoded media: {}", e);
+            None
+        }
+    }
+}
+
+/// Handle decoding and sending files
+pub async fn handle_decode_file(
+    bot: &Bot,
+    db: &Option<DbClient>,
+    user_id: i64,
+    chat_id: ChatId,
+    file: &EncodedFile,
+    storage_chat_id: Option<ChatId>,
+) -> ResponseResult<()> {
+    if is_thumbnail(&file.file_id) {
+        bot.send_message(
+            chat_id,
+            format!(
+                "🔓 Decoded file ID:\n\n{}\n\n⚠️ This is a thumbnail\\. Telegram only serves thumbnails with their file and cannot send them on their own\\.",
+                code_inline(&file.file_id),
+            )
+        )
+        .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+        .await?;
+        return Ok(());
+    }
+
+    // Try every way of sending the file. If all fail, fall back to showing the file_id as text
+    let record = own_encoded_media(db, user_id, &file.file_id).await;
+    match send_file_with_fallbacks(bot, chat_id, file, storage_chat_id, record.as_ref()).await {
+        Some(step) => log::info!("Sent decoded file using {}", step),
+        None => {
+            bot.send_message(
+                chat_id,
+                format!(
+                    "🔓 Decoded file ID:\n\n{}\n\n⚠️ Unable to send this file\\. It may have been deleted or is no longer accessible\\.\n\n{}",
+                    code_inline(&file.file_id),
+                    escape(&describe_file_id(&file.file_id)),
+                )
+            )
+            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Send a decoded file, trying in order: the copy in the storage channel, the
+/// type in its file_id, the type recorded in the payload, a plain document,
+/// and finally a copy of the message it was encoded from
+/// The storage copy and the original message come from the user's own `record`
+/// Returns the step that worked
+async fn send_file_with_fallbacks(
+    bot: &Bot,
+    chat_id: ChatId,
+    file: &EncodedFile,
+    storage_chat_id: Option<ChatId>,
+    record: Option<&EncodedMediaRecord>,
+) -> Option<&'static str> {
+    // The stored copy works even if the file_id was issued to another bot
+    if let Some(storage_chat_id) = storage_chat_id
+        && let Some(message_id) = record.and_then(|record| record.storage_message_in(storage_chat_id.0))
+    {
+        let message_id = MessageId(message_id);
+        match bot.copy_message(chat_id, storage_chat_id, message_id).await {
+            Ok(_) => return Some("the storage channel copy"),
+            Err(e) => log::warn!("Copying from the storage channel failed: {}", e),
+        }
+        match bot.forward_message(chat_id, storage_chat_id, message_id).await {
+            Ok(_) => return Some("a forward from the storage channel"),
+            Err(e) => log::warn!("Forwarding from the storage channel failed: {}", e),
+        }
+    }
+
+    let file_id = file.file_id.as_str();
+    if is_thumbnail(file_id) {
+        return None;
+    }
+
+    let detected = decode_file_type(file_id).ok().and_then(FileType::send_method);
+
+    if let Some(method) = detected {
+        match send_file_as(bot, chat_id, file_id, method).await {
+            Ok(()) => return Some("the file_id type"),
+            Err(e) => log::warn!("Sending decoded file as {:?} failed: {}", method, e),
+        }
+    }
+
+    let mut tried = vec![detected];
+    if let Some(method) = file.send_method
+        && !tried.contains(&Some(method))
+    {
+        tried.push(Some(method));
+        match send_file_as(bot, chat_id, file_id, method).await {
+            Ok(()) => return Some("the recorded type"),
+            Err(e) => log::warn!("Sending decoded file as recorded {:?} failed: {}", method, e),
+        }
+    }
+
+    if !tried.contains(&Some(SendMethod::Document)) {
+        match send_file_as(bot, chat_id, file_id, SendMethod::Document).await {
+            Ok(()) => return Some("a document"),
+            Err(e) => log::warn!("Sending decoded file as a document failed: {}", e),
+        }
+    }
+
+    if let Some(origin) = record.and_then(|record| record.origin) {
+        match bot
+            .copy_message(chat_id, ChatId(origin.chat_id), MessageId(origin.message_id))
+            .await
+        {
+            Ok(_) => return Some("a copy of the original message"),
+            Err(e) => log::warn!("Copying the original message failed: {}", e),
+        }
+    }
+
+    None
+}
+
+/// Rebuild the canonical form of a file_id, repairing ids that were re-encoded
+/// differently (for example with a non-canonical run-length encoding)
+/// Ids that cannot be parsed are returned unchanged
+pub fn canonical_file_id(file_id: &str) -> String {
+    match parse_file_id(file_id) {
+        Ok(parsed) => parsed.to_file_id(),
+        Err(_) => file_id.to_string(),
+    }
+}
+
+/// Thumbnails have no send method, no fallback can deliver them
+fn is_thumbnail(file_id: &str) -> bool {
+    matches!(decode_file_type(file_id), Ok(FileType::Thumbnail | FileType::EncryptedThumbnail))
+}
+
+/// Explain what a file_id points to, or why it cannot be read
+pub fn describe_file_id(file_id: &str) -> String {
+    match parse_file_id(file_id) {
+        Ok(parsed) => parsed.describe(),
+        Err(e) => format!("Unreadable file ID: {}", e),
+    }
+}
+
+/// Handle decoding and sending an album
+pub async fn handle_decode_album(
+    bot: &Bot,
+    db: &Option<DbClient>,
+    user_id: i64,
+    chat_id: ChatId,
+    file_ids: &[String],
+) -> ResponseResult<()> {
+    if try_send_album(bot, chat_id, file_ids).await.is_ok() {
+        return Ok(());
+    }
+
+    // The album could not be sent as a whole, send what is still available one by one
+    let mut failed = Vec::new();
+    for file_id in file_ids {
+        let record = own_encoded_media(db, user_id, file_id).await;
+        if send_file_with_fallbacks(bot, chat_id, &EncodedFile::new(file_id), None, record.as_ref()).await.is_none() {
+            failed.push(code_inline(file_id));
+        }
+    }
+
+    if !failed.is_empty() {
+        bot.send_message(
+            chat_id,
+            format!("🔓 Decoded file IDs:\n\n{}\n\n⚠️ Unable to send {} of {} album items\\. They may have been deleted or are no longer accessible\\.", failed.join("\n"), failed.len(), file_ids.len())
+        )
+        .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Handle decoding and recreating non-file content
+pub async fn handle_decode_content(bot: &Bot, chat_id: ChatId, content: &SharedContent) -> ResponseResult<()> {
+    if let Err(e) = send_shared_content(bot, chat_id, content).await {
+        log::warn!("Failed to recreate {}: {}", content.type_name(), e);
+        bot.send_message(
+            chat_id,
+            format!("⚠️ Unable to recreate this {}.", content.type_name()),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Recreate non-file content with the matching Bot API call
+async fn send_shared_content(bot: &Bot, chat_id: ChatId, content: &SharedContent) -> ResponseResult<()> {
+    match content {
+        SharedContent::Location { latitude, longitude } => {
+            bot.send_location(chat_id, *latitude, *longitude).await?;
+        }
+        SharedContent::Venue { latitude, longitude, title, address, foursquare_id, google_place_id } => {
+            let mut request = bot.send_venue(chat_id, *latitude, *longitude, title, address);
+            if let Some(foursquare_id) = foursquare_id {
+                request = request.foursquare_id(foursquare_id);
+            }
+            if let Some(google_place_id) = google_place_id {
+                request = request.google_place_id(google_place_id);
+            }
+            request.await?;
+        }
+        SharedContent::Contact { phone_number, first_name, last_name, vcard } => {
+            let mut request = bot.send_contact(chat_id, phone_number, first_name);
+            if let Some(last_name) = last_name {
+                request = request.last_name(last_name);
+            }
+            if let Some(vcard) = vcard {
+                request = request.vcard(vcard);
+            }
+            request.await?;
+        }
+        SharedContent::Poll { question, options, is_anonymous, allows_multiple_answers } => {
+            bot.send_poll(chat_id, question, options.iter().map(InputPollOption::new))
+                .is_anonymous(*is_anonymous)
+                .allows_multiple_answers(*allows_multiple_answers)
+                .await?;
+        }
+        SharedContent::Dice { emoji } => {
+            // The dice is rolled again, only its kind is preserved
+            let mut request = bot.send_dice(chat_id);
+            if let Ok(dice_emoji) = serde_json::from_value::<DiceEmoji>(serde_json::Value::String(emoji.clone())) {
+                request = request.emoji(dice_emoji);
+            }
+            request.await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Send a file_id with a specific Bot API method
+async fn send_file_as(bot: &Bot, chat_id: ChatId, file_id: &str, method: SendMethod) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let input = InputFile::file_id(FileId(file_id.to_string()));
+
+    match method {
+        SendMethod::Photo => {
+            bot.send_photo(chat_id, input).await?;
+        }
+        SendMethod::Video => {
+            bot.send_video(chat_id, input).await?;
+        }
+        SendMethod::Voice => {
+            bot.send_voice(chat_id, input).await?;
+        }
+        SendMethod::Document => {
+            bot.send_document(chat_id, input).await?;
+        }
+        SendMethod::Sticker => {
+            bot.send_sticker(chat_id, input).await?;
+        }
+        SendMethod::Audio => {
+            bot.send_audio(chat_id, input).await?;
+        }
+        SendMethod::Animation => {
+            bot.send_animation(chat_id, input).await?;
+        }
+        SendMethod::VideoNote => {
+            bot.send_video_note(chat_id, input).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Try to send all file_ids of an album as a single media group
+async fn try_send_album(bot: &Bot, chat_id: ChatId, file_ids: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut media = Vec::with_capacity(file_ids.len());
+
+    for file_id in file_ids {
+        let input = InputFile::file_id(FileId(file_id.to_string()));
+
+        // Albums can only hold photos, videos, audio and documents
+        media.push(match decode_file_type(file_id)?.send_method() {
+            Some(SendMethod::Photo) => InputMedia::Photo(InputMediaPhoto::new(input)),
+            Some(SendMethod::Video) => InputMedia::Video(InputMediaVideo::new(input)),
+            Some(SendMethod::Audio) => InputMedia::Audio(InputMediaAudio::new(input)),
+            Some(SendMethod::Document) => InputMedia::Document(InputMediaDocument::new(input)),
+            _ => return Err("File type cannot be sent in an album".into()),
+        });
+    }
+
+    bot.send_media_group(chat_id, media).await?;
+    Ok(())
+}
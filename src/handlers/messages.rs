@@ -1,33 +1,97 @@
-use teloxide::prelude::*;
-use teloxide::types::{InputFile, FileId};
-use crate::models::{StateStorage, get_user_state, clear_user_state, BotState};
-use crate::utils::{decode_with_file_check, encode, encode_file_id, decode_file_type, FileType};
-use crate::handlers::commands::create_emoji_keyboard;
+use std::time::Duration;
+use chrono::Utc;
+use teloxide::{prelude::*, types::{InlineKeyboardMarkup, MessageId, ThreadId}};
+use crate::models::{BotConfig, DbClient, StateKey, KeyboardOwnerStorage, record_keyboard_owner, record_recent_emoji, StateStorage, MediaGroupStorage, MEDIA_GROUP_WINDOW, get_pending_state, clear_user_state, sweep_expired_states, push_media_group_item, get_media_group, BotState};
+use crate::utils::{decode_payload, encode, has_variation_selectors, encode_album, encode_content, DecodedPayload, EncoderError, SharedContent};
+use crate::handlers::carrier::{resolve_carrier, send_encoded, Carrier, CarrierChoice, INVALID_CARRIER_PROMPT};
+use crate::handlers::commands::{user_emoji_keyboard, LOCKED_MESSAGE_HINT};
+use crate::handlers::media::{extract_file_info, extract_shared_content, encode_and_store_file, handle_decode_file, handle_decode_album, handle_decode_content, previous_encoding_note, FileInfo};
 
-pub async fn message_handler(bot: Bot, msg: Message, state_storage: StateStorage) -> ResponseResult<()> {
+pub async fn message_handler(
+    bot: Bot,
+    msg: Message,
+    state_storage: StateStorage,
+    media_groups: MediaGroupStorage,
+    db: Option<DbClient>,
+    config: BotConfig,
+    keyboard_owners: KeyboardOwnerStorage,
+) -> ResponseResult<()> {
     let user_id = msg.from.as_ref().map(|u| u.id.0 as i64).unwrap_or(0);
-    let state = get_user_state(&state_storage, user_id).await;
+    // Only a flow started in this chat and topic can continue here
+    let state_key = StateKey::new(&msg, user_id);
+    let state = match get_pending_state(&state_storage, &state_key).await {
+        // The sweeper hasn't got to it yet, don't use this message as the emoji
+        Some(pending) if pending.is_expired(Utc::now()) => {
+            clear_user_state(&state_storage, &state_key).await;
+            bot.send_message(msg.chat.id, expired_state_message(&pending.state)).await?;
+            BotState::Idle
+        }
+        Some(pending) => pending.state,
+        None => BotState::Idle,
+    };
+    // Reply in the custom emoji step: one emoji, a sticker, a custom emoji or a word
+    let mut carrier = Carrier::new("");
+    if !matches!(state, BotState::Idle) {
+        match resolve_carrier(&msg) {
+            CarrierChoice::Carrier(choice) => {
+                // Custom emojis only work for the user who sent them, keep recents portable
+                if choice.custom_emoji_id.is_none() {
+                    record_recent_emoji(&db, user_id, &choice.emoji).await;
+                }
+                carrier = choice;
+            }
+            CarrierChoice::Suggestions(suggestions) => {
+                // Keep the state, the user answers with one of the suggestions
+                bot.send_message(
+                    msg.chat.id,
+                    format!("Did you mean one of these? Send the emoji you want:\n\n{}", suggestions.join(" ")),
+                )
+                .await?;
+                return Ok(());
+            }
+            CarrierChoice::Invalid => {
+                bot.send_message(msg.chat.id, INVALID_CARRIER_PROMPT).await?;
+                return Ok(());
+            }
+        }
+    }
 
     match state {
         BotState::AwaitingCustomEmoji { text: original_text } => {
-            let text = msg.text().unwrap_or("").to_string();
-            if !text.is_empty() {
-                handle_custom_emoji(bot, msg, state_storage, user_id, &original_text, &text).await?;
-            }
+            handle_custom_emoji(bot, msg, state_storage, user_id, &original_text, &carrier).await?;
         }
-        BotState::AwaitingFileEmoji { file_id, file_type } => {
-            let text = msg.text().unwrap_or("").to_string();
-            if !text.is_empty() {
-                handle_file_custom_emoji(bot, msg, state_storage, user_id, &file_id, &file_type, &text).await?;
-            }
+        BotState::AwaitingFileEmoji { file_id, file_type, file_unique_id, origin } => {
+            let info = FileInfo { file_id, file_unique_id, file_type, origin };
+            handle_file_custom_emoji(bot, msg, state_storage, db, config, &info, &carrier).await?;
+        }
+        BotState::AwaitingAlbumEmoji { file_ids } => {
+            handle_album_custom_emoji(bot, msg, state_storage, user_id, &file_ids, &carrier).await?;
+        }
+        BotState::AwaitingContentEmoji { content } => {
+            handle_content_custom_emoji(bot, msg, state_storage, user_id, &content, &carrier).await?;
         }
         BotState::Idle => {
             // Check if this is a file message (only in private chats)
-            if matches!(msg.chat.kind, teloxide::types::ChatKind::Private(_)) {
-                if let Some((file_id, file_type)) = extract_file_info(&msg) {
-                    handle_file_message(bot, msg, state_storage, user_id, file_id, file_type).await?;
-                    return Ok(());
-                }
+            if matches!(msg.chat.kind, teloxide::types::ChatKind::Private(_))
+                && let Some(info) = extract_file_info(&msg)
+            {
+                handle_file_message(bot, msg, media_groups, db, keyboard_owners, user_id, info).await?;
+                return Ok(());
+            }
+
+            // Locations, contacts, polls and dice carry no file but can be hidden too
+            if matches!(msg.chat.kind, teloxide::types::ChatKind::Private(_))
+                && let Some(content) = extract_shared_content(&msg)
+            {
+                let keyboard = bot.send_message(
+                    msg.chat.id,
+                    format!("Select an emoji to hide your {}:", content.type_name()),
+                )
+                .reply_parameters(teloxide::types::ReplyParameters::new(msg.id))
+                .reply_markup(user_emoji_keyboard(&db, user_id).await)
+                .await?;
+                record_sender_keyboard(&keyboard_owners, &db, &keyboard, &msg).await;
+                return Ok(());
             }
 
             let text = msg.text().unwrap_or("").to_string();
@@ -36,38 +100,56 @@ pub async fn message_handler(bot: Bot, msg: Message, state_storage: StateStorage
             }
 
             // Only decode messages in private chats
-            if matches!(msg.chat.kind, teloxide::types::ChatKind::Private(_)) {
-                // Try to decode only if the message contains variation selectors
-                if text.chars().any(|c| {
-                    let code = c as u32;
-                    (0xFE00..=0xFE0F).contains(&code) || (0xE0100..=0xE01EF).contains(&code)
-                }) {
-                    if let Ok((is_file, content)) = decode_with_file_check(&text) {
-                        if is_file {
-                            // It's a file_id, try to send the file
-                            handle_decode_file(&bot, &msg, &content).await?;
-                            return Ok(());
-                        } else if !content.is_empty() {
-                            // It's regular text
-                            bot.send_message(msg.chat.id, format!("🔓 Decoded message:\n\n{}", content))
-                                .await?;
-                            return Ok(());
-                        }
+            // Try to decode only if the message contains variation selectors
+            if matches!(msg.chat.kind, teloxide::types::ChatKind::Private(_))
+                && has_variation_selectors(&text)
+            {
+                match decode_payload(&text) {
+                    Ok(DecodedPayload::File(file)) => {
+                        // It's a file_id, try to send the file
+                        handle_decode_file(&bot, &db, user_id, msg.chat.id, &file, config.storage_chat_id).await?;
+                        return Ok(());
+                    }
+                    Ok(DecodedPayload::Album(file_ids)) => {
+                        handle_decode_album(&bot, &db, user_id, msg.chat.id, &file_ids).await?;
+                        return Ok(());
                     }
+                    Ok(DecodedPayload::Content(content)) => {
+                        handle_decode_content(&bot, msg.chat.id, &content).await?;
+                        return Ok(());
+                    }
+                    Ok(DecodedPayload::Text(content)) if !content.is_empty() => {
+                        // It's regular text
+                        bot.send_message(msg.chat.id, format!("🔓 Decoded message:\n\n{}", content))
+                            .await?;
+                        return Ok(());
+                    }
+                    Ok(DecodedPayload::Locked(_)) => {
+                        bot.send_message(msg.chat.id, LOCKED_MESSAGE_HINT)
+                            .reply_parameters(teloxide::types::ReplyParameters::new(msg.id))
+                            .await?;
+                        return Ok(());
+                    }
+                    Err(e @ EncoderError::Expired(_)) => {
+                        bot.send_message(msg.chat.id, format!("⌛ {}", e)).await?;
+                        return Ok(());
+                    }
+                    _ => {}
                 }
             }
 
             // Only show emoji keyboard in private chats
             // In groups/channels, only respond to commands or encoded messages
             if matches!(msg.chat.kind, teloxide::types::ChatKind::Private(_)) {
-                let keyboard = create_emoji_keyboard();
-                bot.send_message(
+                let keyboard = user_emoji_keyboard(&db, user_id).await;
+                let keyboard = bot.send_message(
                     msg.chat.id,
                     "Select an emoji to encode your message:",
                 )
                 .reply_parameters(teloxide::types::ReplyParameters::new(msg.id))
                 .reply_markup(keyboard)
                 .await?;
+                record_sender_keyboard(&keyboard_owners, &db, &keyboard, &msg).await;
             }
         }
     }
@@ -75,103 +157,141 @@ pub async fn message_handler(bot: Bot, msg: Message, state_storage: StateStorage
     Ok(())
 }
 
-/// Extract file_id and file_type from a message
-/// Returns (file_id, file_type) if the message contains a supported file
-fn extract_file_info(msg: &Message) -> Option<(String, String)> {
-    if let Some(photo) = msg.photo() {
-        // Get the largest photo size
-        if let Some(largest) = photo.last() {
-            return Some((largest.file.id.to_string(), "photo".to_string()));
+/// How often expired states are cleared
+const STATE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tells a user the bot stopped waiting for their emoji
+pub fn expired_state_message(state: &BotState) -> String {
+    format!(
+        "⌛ I stopped waiting for an emoji to hide your {}. Send it again to start over.",
+        state.subject()
+    )
+}
+
+/// Clear expired states in the background and tell their users
+pub async fn run_state_sweeper(bot: Bot, state_storage: StateStorage) {
+    let mut interval = tokio::time::interval(STATE_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (key, state) in sweep_expired_states(&state_storage).await {
+            let mut notice = bot.send_message(ChatId(key.chat_id), expired_state_message(&state));
+            if let Some(thread_id) = key.thread_id {
+                notice = notice.message_thread_id(ThreadId(MessageId(thread_id)));
+            }
+            // The chat may be gone or the bot removed from it
+            if let Err(e) = notice.await {
+                log::warn!("Failed to tell {} their pending step expired: {}", key, e);
+            }
         }
     }
+}
 
-    if let Some(video) = msg.video() {
-        return Some((video.file.id.to_string(), "video".to_string()));
-    }
+/// Handle when a user sends a file
+async fn handle_file_message(
+    bot: Bot,
+    msg: Message,
+    media_groups: MediaGroupStorage,
+    db: Option<DbClient>,
+    keyboard_owners: KeyboardOwnerStorage,
+    user_id: i64,
+    info: FileInfo,
+) -> ResponseResult<()> {
+    // Albums arrive as one update per item, collect them and answer once
+    if let Some(media_group_id) = msg.media_group_id() {
+        let media_group_id = media_group_id.0.clone();
+        let is_first = push_media_group_item(&media_groups, &media_group_id, msg.id.0, &info.file_id).await;
 
-    if let Some(audio) = msg.audio() {
-        return Some((audio.file.id.to_string(), "audio".to_string()));
-    }
+        if is_first {
+            let chat_id = msg.chat.id;
+            let owner = msg.from.clone();
+            let keyboard = user_emoji_keyboard(&db, user_id).await;
+            tokio::spawn(async move {
+                tokio::time::sleep(MEDIA_GROUP_WINDOW).await;
+                let sent = send_album_keyboard(&bot, chat_id, &media_groups, &media_group_id, keyboard).await;
+                match (sent, owner) {
+                    (Ok(Some(keyboard)), Some(owner)) => record_keyboard_owner(&keyboard_owners, &db, &keyboard, &owner).await,
+                    (Ok(_), _) => {}
+                    (Err(e), _) => log::error!("Failed to send album keyboard: {}", e),
+                }
+            });
+        }
 
-    if let Some(document) = msg.document() {
-        return Some((document.file.id.to_string(), "document".to_string()));
+        return Ok(());
     }
 
-    if let Some(sticker) = msg.sticker() {
-        return Some((sticker.file.id.to_string(), "sticker".to_string()));
-    }
+    let keyboard = user_emoji_keyboard(&db, user_id).await;
+    let type_name = info.file_type.name();
 
-    if let Some(voice) = msg.voice() {
-        return Some((voice.file.id.to_string(), "voice".to_string()));
-    }
+    // Don't set state - keep user in Idle so they can send multiple files
+    // The callback handler will get file info from the replied message
 
-    if let Some(video_note) = msg.video_note() {
-        return Some((video_note.file.id.to_string(), "video note".to_string()));
+    let mut prompt = format!("Select an emoji to hide your {}:", type_name);
+    if let Some(note) = previous_encoding_note(&db, user_id, &info).await {
+        prompt = format!("{}\n\n{}", note, prompt);
     }
 
-    if let Some(animation) = msg.animation() {
-        return Some((animation.file.id.to_string(), "animation".to_string()));
-    }
+    let keyboard = bot.send_message(msg.chat.id, prompt)
+    .reply_parameters(teloxide::types::ReplyParameters::new(msg.id))
+    .reply_markup(keyboard)
+    .await?;
+    record_sender_keyboard(&keyboard_owners, &db, &keyboard, &msg).await;
 
-    None
+    Ok(())
 }
 
-/// Get human-readable file type name
-fn get_file_type_name(file_type: &str) -> &str {
-    match file_type {
-        "photo" => "photo",
-        "video" => "video",
-        "audio" => "audio",
-        "document" => "document",
-        "sticker" => "sticker",
-        "voice" => "voice message",
-        "video note" => "video note",
-        "animation" => "animation",
-        _ => "file",
+/// Remember that a keyboard answers the sender of a message
+async fn record_sender_keyboard(
+    keyboard_owners: &KeyboardOwnerStorage,
+    db: &Option<DbClient>,
+    keyboard: &Message,
+    request: &Message,
+) {
+    if let Some(user) = &request.from {
+        record_keyboard_owner(keyboard_owners, db, keyboard, user).await;
     }
 }
 
-/// Handle when a user sends a file
-async fn handle_file_message(
-    bot: Bot,
-    msg: Message,
-    _state_storage: StateStorage,
-    _user_id: i64,
-    _file_id: String,
-    file_type: String,
-) -> ResponseResult<()> {
-    let keyboard = create_emoji_keyboard();
-    let type_name = get_file_type_name(&file_type);
+/// Send a single emoji keyboard for a collected album, replying to its first item
+/// Returns the keyboard message, None if the album is gone
+async fn send_album_keyboard(
+    bot: &Bot,
+    chat_id: ChatId,
+    media_groups: &MediaGroupStorage,
+    media_group_id: &str,
+    keyboard: InlineKeyboardMarkup,
+) -> ResponseResult<Option<Message>> {
+    let Some(group) = get_media_group(media_groups, media_group_id).await else {
+        return Ok(None);
+    };
+    let Some(first_message_id) = group.first_message_id() else {
+        return Ok(None);
+    };
 
-    // Don't set state - keep user in Idle so they can send multiple files
-    // The callback handler will get file info from the replied message
-
-    bot.send_message(
-        msg.chat.id,
-        format!("Select an emoji to hide your {}:", type_name),
+    let sent = bot.send_message(
+        chat_id,
+        format!("Select an emoji to hide your album ({} items):", group.items.len()),
     )
-    .reply_parameters(teloxide::types::ReplyParameters::new(msg.id))
+    .reply_parameters(teloxide::types::ReplyParameters::new(teloxide::types::MessageId(first_message_id)))
     .reply_markup(keyboard)
     .await?;
 
-    Ok(())
+    Ok(Some(sent))
 }
 
-/// Handle when user sends custom emoji for a file
-async fn handle_file_custom_emoji(
+/// Handle when user sends custom emoji for an album
+async fn handle_album_custom_emoji(
     bot: Bot,
     msg: Message,
     state_storage: StateStorage,
     user_id: i64,
-    file_id: &str,
-    _file_type: &str,
-    emoji: &str,
+    file_ids: &[String],
+    carrier: &Carrier,
 ) -> ResponseResult<()> {
-    clear_user_state(&state_storage, user_id).await;
+    clear_user_state(&state_storage, &StateKey::new(&msg, user_id)).await;
 
-    match encode_file_id(emoji, file_id) {
+    match encode_album(&carrier.emoji, file_ids) {
         Ok(encoded) => {
-            bot.send_message(msg.chat.id, &encoded).await?;
+            send_encoded(&bot, msg.chat.id, &encoded, carrier).await?;
         }
         Err(e) => {
             bot.send_message(
@@ -185,63 +305,56 @@ async fn handle_file_custom_emoji(
     Ok(())
 }
 
-/// Handle decoding and sending files
-async fn handle_decode_file(bot: &Bot, msg: &Message, file_id: &str) -> ResponseResult<()> {
-    // Try to send the file. If it fails, fall back to showing the file_id as text
-    let send_result = try_send_file(bot, msg.chat.id, file_id).await;
+/// Handle when user sends custom emoji for a location, contact, poll or dice
+async fn handle_content_custom_emoji(
+    bot: Bot,
+    msg: Message,
+    state_storage: StateStorage,
+    user_id: i64,
+    content: &SharedContent,
+    carrier: &Carrier,
+) -> ResponseResult<()> {
+    clear_user_state(&state_storage, &StateKey::new(&msg, user_id)).await;
 
-    match send_result {
-        Ok(_) => {
-            // File sent successfully
-            Ok(())
+    match encode_content(&carrier.emoji, content) {
+        Ok(encoded) => {
+            send_encoded(&bot, msg.chat.id, &encoded, carrier).await?;
         }
-        Err(_) => {
-            // Failed to send file, show the file_id as text instead
+        Err(e) => {
             bot.send_message(
                 msg.chat.id,
-                format!("🔓 Decoded file ID:\n\n`{}`\n\n⚠️ Unable to send this file. It may have been deleted or is no longer accessible.", file_id)
+                format!("❌ Error encoding: {}", e),
             )
-            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
             .await?;
-            Ok(())
         }
     }
+
+    Ok(())
 }
 
-/// Try to send a file by its file_id
-/// Decodes the file_id to determine the type and sends it directly
-async fn try_send_file(bot: &Bot, chat_id: ChatId, file_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Decode the file_id to get the file type
-    let file_type = decode_file_type(file_id)?;
+/// Handle when user sends custom emoji for a file
+async fn handle_file_custom_emoji(
+    bot: Bot,
+    msg: Message,
+    state_storage: StateStorage,
+    db: Option<DbClient>,
+    config: BotConfig,
+    info: &FileInfo,
+    carrier: &Carrier,
+) -> ResponseResult<()> {
+    let user_id = msg.from.as_ref().map(|u| u.id.0 as i64).unwrap_or(0);
+    clear_user_state(&state_storage, &StateKey::new(&msg, user_id)).await;
 
-    // Send based on the decoded type
-    match file_type {
-        FileType::Photo => {
-            bot.send_photo(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Video => {
-            bot.send_video(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Voice => {
-            bot.send_voice(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Document => {
-            bot.send_document(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Sticker => {
-            bot.send_sticker(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Audio => {
-            bot.send_audio(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::Animation => {
-            bot.send_animation(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
-        }
-        FileType::VideoNote => {
-            bot.send_video_note(chat_id, InputFile::file_id(FileId(file_id.to_string()))).await?;
+    match encode_and_store_file(&bot, &db, &config, user_id, info, &carrier.emoji).await {
+        Ok(encoded) => {
+            send_encoded(&bot, msg.chat.id, &encoded, carrier).await?;
         }
-        FileType::Unknown => {
-            return Err("Unknown file type".into());
+        Err(e) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("❌ Error encoding: {}", e),
+            )
+            .await?;
         }
     }
 
@@ -254,13 +367,13 @@ async fn handle_custom_emoji(
     state_storage: StateStorage,
     user_id: i64,
     original_text: &str,
-    emoji: &str,
+    carrier: &Carrier,
 ) -> ResponseResult<()> {
-    clear_user_state(&state_storage, user_id).await;
+    clear_user_state(&state_storage, &StateKey::new(&msg, user_id)).await;
 
-    match encode(emoji, original_text) {
+    match encode(&carrier.emoji, original_text) {
         Ok(encoded) => {
-            bot.send_message(msg.chat.id, &encoded).await?;
+            send_encoded(&bot, msg.chat.id, &encoded, carrier).await?;
         }
         Err(e) => {
             bot.send_message(
@@ -2,8 +2,10 @@ pub mod commands;
 pub mod messages;
 pub mod callbacks;
 pub mod inline;
+pub mod media;
+pub mod carrier;
 
-pub use commands::{start_handler, stats_handler, about_handler, help_handler, encode_command_handler, decode_command_handler};
-pub use messages::message_handler;
-pub use callbacks::callback_handler;
-pub use inline::inline_query_handler;
+pub use commands::{start_handler, stats_handler, about_handler, help_handler, encode_command_handler, decode_command_handler, favorites_command_handler, buttons_command_handler, cancel_command_handler, inspect_command_handler};
+pub use messages::{message_handler, run_state_sweeper};
+pub use callbacks::{callback_handler, authorize_keyboard_press};
+pub use inline::{inline_query_handler, chosen_inline_result_handler};
@@ -4,8 +4,8 @@ mod utils;
 
 use teloxide::prelude::*;
 use teloxide::utils::command::BotCommands;
-use handlers::{start_handler, stats_handler, about_handler, message_handler, callback_handler, inline_query_handler};
-use models::{DbClient, create_state_storage};
+use handlers::{start_handler, stats_handler, about_handler, message_handler, callback_handler, authorize_keyboard_press, inline_query_handler, chosen_inline_result_handler, run_state_sweeper};
+use models::{BotConfig, DbClient, KeyboardOwnerStorage, MediaGroupStorage, StateStorage, create_state_storage, create_inline_payload_storage, create_keyboard_owner_storage};
 
 #[tokio::main]
 async fn main() {
@@ -24,11 +24,11 @@ async fn main() {
             String::new()
         });
 
-    let admin_ids: Vec<i64> = std::env::var("ADMIN_IDS")
-        .unwrap_or_default()
-        .split(',')
-        .filter_map(|s| s.trim().parse().ok())
-        .collect();
+    let config = BotConfig::from_env();
+    if let Some(storage_chat_id) = config.storage_chat_id {
+        log::info!("Storing encoded media in chat {}", storage_chat_id);
+    }
+    log::info!("Keeping conversation state in {:?}", config.state_backend);
 
     let db = if !mongodb_uri.is_empty() {
         match DbClient::new(&mongodb_uri).await {
@@ -45,7 +45,13 @@ async fn main() {
         None
     };
 
-    let state_storage = create_state_storage();
+    let state_storage = create_state_storage(&config.state_backend, &db).await;
+    // Albums are collected in the state store too
+    let media_groups: MediaGroupStorage = state_storage.clone();
+    let inline_payloads = create_inline_payload_storage();
+    let keyboard_owners = create_keyboard_owner_storage();
+
+    tokio::spawn(run_state_sweeper(bot.clone(), state_storage.clone()));
 
     log::info!("Bot started successfully!");
 
@@ -55,34 +61,80 @@ async fn main() {
                 .filter_command::<Command>()
                 .endpoint({
                     let db = db.clone();
-                    let admin_ids = admin_ids.clone();
+                    let config = config.clone();
+                    let state_storage = state_storage.clone();
+                    let keyboard_owners = keyboard_owners.clone();
                     move |bot: Bot, msg: Message, cmd: Command| {
                         let db = db.clone();
-                        let admin_ids = admin_ids.clone();
+                        let config = config.clone();
+                        let state_storage = state_storage.clone();
+                        let keyboard_owners = keyboard_owners.clone();
                         async move {
-                            command_handler(bot, msg, cmd, db, admin_ids).await
+                            command_handler(bot, msg, cmd, db, config, state_storage, keyboard_owners).await
                         }
                     }
                 })
         ).branch({
             let state_storage = state_storage.clone();
+            let media_groups = media_groups.clone();
+            let db = db.clone();
+            let config = config.clone();
+            let keyboard_owners = keyboard_owners.clone();
             dptree::endpoint(move |bot: Bot, msg: Message| {
                 let state_storage = state_storage.clone();
+                let media_groups = media_groups.clone();
+                let db = db.clone();
+                let config = config.clone();
+                let keyboard_owners = keyboard_owners.clone();
                 async move {
-                    message_handler(bot, msg, state_storage).await
+                    message_handler(bot, msg, state_storage, media_groups, db, config, keyboard_owners).await
                 }
             })
         }))
         .branch(Update::filter_callback_query().endpoint({
             let state_storage = state_storage.clone();
+            let media_groups = media_groups.clone();
+            let db = db.clone();
+            let config = config.clone();
+            let inline_payloads = inline_payloads.clone();
+            let keyboard_owners = keyboard_owners.clone();
             move |bot: Bot, q: CallbackQuery| {
                 let state_storage = state_storage.clone();
+                let media_groups = media_groups.clone();
+                let db = db.clone();
+                let config = config.clone();
+                let inline_payloads = inline_payloads.clone();
+                let keyboard_owners = keyboard_owners.clone();
                 async move {
-                    callback_handler(bot, q, state_storage).await
+                    let Some(q) = authorize_keyboard_press(&bot, q, &keyboard_owners, &db).await? else {
+                        return Ok(());
+                    };
+                    callback_handler(bot, q, state_storage, media_groups, db, config, inline_payloads).await
                 }
             }
         }))
-        .branch(Update::filter_inline_query().endpoint(inline_query_handler));
+        .branch(Update::filter_inline_query().endpoint({
+            let inline_payloads = inline_payloads.clone();
+            let db = db.clone();
+            move |bot: Bot, q: InlineQuery| {
+                let inline_payloads = inline_payloads.clone();
+                let db = db.clone();
+                async move {
+                    inline_query_handler(bot, q, inline_payloads, db).await
+                }
+            }
+        }))
+        .branch(Update::filter_chosen_inline_result().endpoint({
+            let inline_payloads = inline_payloads.clone();
+            let db = db.clone();
+            move |result: ChosenInlineResult| {
+                let inline_payloads = inline_payloads.clone();
+                let db = db.clone();
+                async move {
+                    chosen_inline_result_handler(result, inline_payloads, db).await
+                }
+            }
+        }));
 
     Dispatcher::builder(bot, handler)
         .enable_ctrlc_handler()
@@ -110,6 +162,14 @@ enum Command {
     Decode(String),
     #[command(description = "Decode emoji or reply to a message")]
     Show(String),
+    #[command(description = "Explain what a file_id or encoded file points to")]
+    Inspect(String),
+    #[command(description = "Pin or unpin favourite emojis")]
+    Favorites(String),
+    #[command(description = "Stop waiting for a custom emoji")]
+    Cancel,
+    #[command(description = "Choose who can press emoji keyboards in a group")]
+    Buttons(String),
 }
 
 async fn command_handler(
@@ -117,7 +177,9 @@ async fn command_handler(
     msg: Message,
     cmd: Command,
     db: Option<DbClient>,
-    admin_ids: Vec<i64>,
+    config: BotConfig,
+    state_storage: StateStorage,
+    keyboard_owners: KeyboardOwnerStorage,
 ) -> ResponseResult<()> {
     match cmd {
         Command::Start => {
@@ -136,17 +198,30 @@ async fn command_handler(
         }
         Command::Stats => {
             if let Some(db) = db {
-                stats_handler(bot, msg, db, admin_ids).await
+                stats_handler(bot, msg, db, config.admin_ids).await
             } else {
                 bot.send_message(msg.chat.id, "❌ Stats unavailable (database not connected)").await?;
                 Ok(())
             }
         }
         Command::Encode(text) | Command::Hide(text) => {
-            handlers::encode_command_handler(bot, msg, text).await
+            // Albums are collected in the state store
+            handlers::encode_command_handler(bot, msg, text, state_storage, db, config, keyboard_owners).await
         }
         Command::Decode(text) | Command::Show(text) => {
-            handlers::decode_command_handler(bot, msg, text).await
+            handlers::decode_command_handler(bot, msg, text, db, config.storage_chat_id).await
+        }
+        Command::Inspect(text) => {
+            handlers::inspect_command_handler(bot, msg, text).await
+        }
+        Command::Favorites(text) => {
+            handlers::favorites_command_handler(bot, msg, text, db).await
+        }
+        Command::Cancel => {
+            handlers::cancel_command_handler(bot, msg, state_storage).await
+        }
+        Command::Buttons(text) => {
+            handlers::buttons_command_handler(bot, msg, text, db).await
         }
     }
 }
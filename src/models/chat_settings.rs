@@ -0,0 +1,30 @@
+use crate::models::DbClient;
+
+/// Per-group settings, changed by the group's admins
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatSettings {
+    /// Admins may press emoji keyboards sent to other members
+    pub admins_can_press: bool,
+}
+
+/// A chat's settings, the defaults when running without a database
+pub async fn load_chat_settings(db: &Option<DbClient>, chat_id: i64) -> ChatSettings {
+    let Some(db) = db else {
+        return ChatSettings::default();
+    };
+
+    match db.find_chat_settings(chat_id).await {
+        Ok(Some(record)) => ChatSettings {
+            admins_can_press: record.admins_can_press,
+        },
+        Ok(None) => ChatSettings::default(),
+        Err(e) => {
+            log::error!("Failed to load settings of chat {}: {}", chat_id, e);
+            ChatSettings::default()
+        }
+    }
+}
+
+pub async fn save_chat_settings(db: &DbClient, chat_id: i64, settings: &ChatSettings) -> Result<(), mongodb::error::Error> {
+    db.save_chat_settings(chat_id, settings.admins_can_press).await
+}
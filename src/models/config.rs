@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+use teloxide::types::ChatId;
+
+/// File the file-backed state store uses unless STATE_FILE is set
+const DEFAULT_STATE_FILE: &str = "bot_state.redb";
+
+/// Where pending conversation states are kept
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum StateBackend {
+    /// Lost on restart
+    #[default]
+    Memory,
+    /// In MongoDB, shared by all replicas
+    Mongo,
+    /// In an embedded database file
+    File(PathBuf),
+}
+
+/// Settings read from the environment at startup
+#[derive(Debug, Clone, Default)]
+pub struct BotConfig {
+    pub admin_ids: Vec<i64>,
+    /// Private channel encoded media is copied to, so emojis keep working
+    /// after a token change and across bot instances
+    pub storage_chat_id: Option<ChatId>,
+    pub state_backend: StateBackend,
+}
+
+impl BotConfig {
+    pub fn from_env() -> Self {
+        let admin_ids = std::env::var("ADMIN_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|s| s.trim().parse().ok())
+            .collect();
+
+        let storage_chat_id = match std::env::var("STORAGE_CHAT_ID") {
+            Ok(value) if !value.trim().is_empty() => match value.trim().parse() {
+                Ok(id) => Some(ChatId(id)),
+                Err(_) => {
+                    log::warn!("STORAGE_CHAT_ID is not a chat id, media will not be stored: {}", value);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let state_backend = match std::env::var("STATE_BACKEND").unwrap_or_default().trim() {
+            "" | "memory" => StateBackend::Memory,
+            "mongodb" | "mongo" => StateBackend::Mongo,
+            "file" => StateBackend::File(
+                std::env::var("STATE_FILE")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|_| PathBuf::from(DEFAULT_STATE_FILE)),
+            ),
+            other => {
+                log::warn!("Unknown STATE_BACKEND {}, keeping state in memory", other);
+                StateBackend::Memory
+            }
+        };
+
+        Self {
+            admin_ids,
+            storage_chat_id,
+            state_backend,
+        }
+    }
+}
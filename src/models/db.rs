@@ -1,5 +1,11 @@
-use mongodb::{Client, Database, bson::{doc, DateTime as BsonDateTime}};
+use std::time::Duration;
+use mongodb::{Client, Database, IndexModel, bson::{doc, DateTime as BsonDateTime}, options::{IndexOptions, ReturnDocument}};
 use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use teloxide::types::User;
+use crate::models::{BotState, PendingState, StateKey, Whisper, FAVORITES_LIMIT, OWNER_RETENTION, PAYLOAD_RETENTION, RECENT_LIMIT};
+use crate::models::media_group::{MediaGroup, MEDIA_GROUP_RETENTION};
+use crate::utils::MessageOrigin;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatRecord {
@@ -12,6 +18,187 @@ pub struct ChatRecord {
     pub last_interaction: BsonDateTime,
 }
 
+/// A file a user has hidden in an emoji, one record per user and unique file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodedMediaRecord {
+    pub user_id: i64,
+    pub file_unique_id: String,
+    pub file_id: String,
+    pub file_type: String,
+    pub emoji: String,
+    pub first_encoded: BsonDateTime,
+    pub last_encoded: BsonDateTime,
+    /// Copy of the file in the storage channel, if one is configured
+    #[serde(default)]
+    pub storage_chat_id: Option<i64>,
+    #[serde(default)]
+    pub storage_message_id: Option<i32>,
+    /// Message the file was last hidden from, copied when its file_id stops working
+    #[serde(default)]
+    pub origin: Option<MessageOrigin>,
+}
+
+/// Messages a hidden file can be copied from, see EncodedMediaRecord
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MediaCopies {
+    /// (chat id, message id) of the copy in the storage channel
+    pub storage: Option<(i64, i32)>,
+    pub origin: Option<MessageOrigin>,
+}
+
+impl EncodedMediaRecord {
+    /// Message id of the stored copy, if it is in the given storage channel
+    pub fn storage_message_in(&self, storage_chat_id: i64) -> Option<i32> {
+        match self.storage_chat_id {
+            Some(chat_id) if chat_id == storage_chat_id => self.storage_message_id,
+            _ => None,
+        }
+    }
+}
+
+/// A secret sent through inline mode, see models::inline_payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InlinePayloadRecord {
+    pub key: String,
+    pub text: String,
+    pub created_at: BsonDateTime,
+}
+
+/// A whisper and the key of its reveal button
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WhisperRecord {
+    pub key: String,
+    #[serde(flatten)]
+    pub whisper: Whisper,
+    pub created_at: BsonDateTime,
+}
+
+/// An album being collected from its separate updates, see models::media_group
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaGroupRecord {
+    pub media_group_id: String,
+    /// (message_id, file_id) pairs in the order they arrived
+    pub items: Vec<(i32, String)>,
+    pub created_at: BsonDateTime,
+}
+
+/// The user an emoji keyboard was sent to, see models::keyboard_owner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyboardOwnerRecord {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub owner: User,
+    pub created_at: BsonDateTime,
+}
+
+/// Carrier emojis a user pinned or used recently, see models::user_emojis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEmojisRecord {
+    pub user_id: i64,
+    #[serde(default)]
+    pub favorites: Vec<String>,
+    #[serde(default)]
+    pub recent: Vec<String>,
+    pub updated_at: BsonDateTime,
+}
+
+/// Settings of a group, see models::chat_settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatSettingsRecord {
+    pub chat_id: i64,
+    #[serde(default)]
+    pub admins_can_press: bool,
+    pub updated_at: BsonDateTime,
+}
+
+/// A user's pending conversation state, see models::state_store
+/// Records saved before expiry existed have no timestamps and are dropped without notice
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserStateRecord {
+    /// Set to user_id by migrate_user_states for records saved before states were scoped
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    pub user_id: i64,
+    #[serde(default)]
+    pub thread_id: Option<i32>,
+    pub state: BotState,
+    #[serde(default)]
+    pub created_at: Option<BsonDateTime>,
+    #[serde(default)]
+    pub expires_at: Option<BsonDateTime>,
+    pub updated_at: BsonDateTime,
+}
+
+impl UserStateRecord {
+    fn key(&self) -> StateKey {
+        match self.chat_id {
+            Some(chat_id) => StateKey {
+                chat_id,
+                user_id: self.user_id,
+                thread_id: self.thread_id,
+            },
+            None => StateKey::private(self.user_id),
+        }
+    }
+
+    /// None for states saved before expiry existed, they are dropped without notice
+    fn into_pending(self) -> Option<PendingState> {
+        let to_chrono = |time: BsonDateTime| DateTime::from_timestamp_millis(time.timestamp_millis());
+        Some(PendingState {
+            state: self.state,
+            created_at: self.created_at.and_then(to_chrono).unwrap_or(DateTime::UNIX_EPOCH),
+            expires_at: to_chrono(self.expires_at?)?,
+        })
+    }
+}
+
+/// Oldest creation time of a record still within retention
+fn created_after(retention: Duration) -> BsonDateTime {
+    BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - retention.as_millis() as i64)
+}
+
+/// Matches the state of one user in one chat and topic
+fn state_filter(key: &StateKey) -> mongodb::bson::Document {
+    doc! {
+        "chat_id": key.chat_id,
+        "user_id": key.user_id,
+        "thread_id": key.thread_id,
+    }
+}
+
+/// Moves an emoji to the front of a user's recent list, keeping RECENT_LIMIT
+/// A pipeline, so dropping the old copy and adding the new one is one atomic update
+fn recent_emoji_update(emoji: &str) -> Vec<mongodb::bson::Document> {
+    vec![doc! {
+        "$set": {
+            "recent": {
+                "$slice": [
+                    {
+                        "$concatArrays": [
+                            [emoji],
+                            {
+                                "$filter": {
+                                    "input": { "$ifNull": ["$recent", []] },
+                                    "cond": { "$ne": ["$$this", emoji] },
+                                }
+                            },
+                        ]
+                    },
+                    RECENT_LIMIT as i64,
+                ]
+            },
+            "updated_at": "$$NOW",
+        }
+    }]
+}
+
+/// Matches a user's record only while the emoji isn't pinned and there is room for it
+fn favorite_slot_filter(user_id: i64, emoji: &str) -> mongodb::bson::Document {
+    let mut filter = doc! { "user_id": user_id, "favorites": { "$ne": emoji } };
+    filter.insert(format!("favorites.{}", FAVORITES_LIMIT - 1), doc! { "$exists": false });
+    filter
+}
+
 #[derive(Debug, Clone)]
 pub struct DbClient {
     pub db: Database,
@@ -21,8 +208,79 @@ impl DbClient {
     pub async fn new(mongodb_uri: &str) -> Result<Self, mongodb::error::Error> {
         let client = Client::with_uri_str(mongodb_uri).await?;
         let db = client.database("emoji_encoder_bot");
+        let client = Self { db };
+
+        // The database works without them, lookups are just slower and secrets linger
+        if let Err(e) = client.create_indexes().await {
+            log::error!("Failed to create database indexes: {}", e);
+        }
+
+        Ok(client)
+    }
 
-        Ok(Self { db })
+    /// Inline secrets and whispers are unique by key and expire after PAYLOAD_RETENTION
+    async fn create_indexes(&self) -> Result<(), mongodb::error::Error> {
+        for name in ["inline_payloads", "whispers"] {
+            let collection = self.db.collection::<mongodb::bson::Document>(name);
+            collection
+                .create_index(
+                    IndexModel::builder()
+                        .keys(doc! { "key": 1 })
+                        .options(IndexOptions::builder().unique(true).build())
+                        .build(),
+                )
+                .await?;
+            collection
+                .create_index(
+                    IndexModel::builder()
+                        .keys(doc! { "created_at": 1 })
+                        .options(IndexOptions::builder().expire_after(PAYLOAD_RETENTION).build())
+                        .build(),
+                )
+                .await?;
+        }
+
+        let media_groups = self.db.collection::<MediaGroupRecord>("media_groups");
+        media_groups
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "media_group_id": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await?;
+        media_groups
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "created_at": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(MEDIA_GROUP_RETENTION.to_std().unwrap_or_default())
+                            .build(),
+                    )
+                    .build(),
+            )
+            .await?;
+
+        let owners = self.db.collection::<KeyboardOwnerRecord>("keyboard_owners");
+        owners
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "chat_id": 1, "message_id": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await?;
+        owners
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "created_at": 1 })
+                    .options(IndexOptions::builder().expire_after(OWNER_RETENTION).build())
+                    .build(),
+            )
+            .await?;
+
+        Ok(())
     }
 
     pub async fn save_chat(
@@ -56,6 +314,402 @@ impl DbClient {
         Ok(())
     }
 
+    /// Find the last time a user hid this file, by its file_unique_id
+    pub async fn find_encoded_media(
+        &self,
+        user_id: i64,
+        file_unique_id: &str,
+    ) -> Result<Option<EncodedMediaRecord>, mongodb::error::Error> {
+        let collection = self.db.collection::<EncodedMediaRecord>("encoded_media");
+
+        collection
+            .find_one(doc! { "user_id": user_id, "file_unique_id": file_unique_id })
+            .await
+    }
+
+    /// Remember that a user hid a file
+    /// Files are deduplicated by file_unique_id, so hiding the same file again
+    /// only refreshes the stored file_id and emoji
+    /// Returns the previous record if the file was hidden before
+    pub async fn record_encoded_media(
+        &self,
+        user_id: i64,
+        file_unique_id: &str,
+        file_id: &str,
+        file_type: &str,
+        emoji: &str,
+        copies: MediaCopies,
+    ) -> Result<Option<EncodedMediaRecord>, mongodb::error::Error> {
+        let collection = self.db.collection::<EncodedMediaRecord>("encoded_media");
+        let now = BsonDateTime::now();
+
+        let filter = doc! { "user_id": user_id, "file_unique_id": file_unique_id };
+        let mut set = doc! {
+            "file_id": file_id,
+            "file_type": file_type,
+            "emoji": emoji,
+            "last_encoded": now,
+        };
+        if let Some((storage_chat_id, storage_message_id)) = copies.storage {
+            set.insert("storage_chat_id", storage_chat_id);
+            set.insert("storage_message_id", storage_message_id);
+        }
+        if let Some(origin) = copies.origin {
+            set.insert("origin", doc! { "chat_id": origin.chat_id, "message_id": origin.message_id });
+        }
+        let update = doc! {
+            "$set": set,
+            "$setOnInsert": {
+                "first_encoded": now,
+            }
+        };
+
+        collection
+            .find_one_and_update(filter, update)
+            .upsert(true)
+            .return_document(ReturnDocument::Before)
+            .await
+    }
+
+    /// Store a secret for the inline reveal button, keeping the first copy of a key
+    pub async fn store_inline_payload(&self, key: &str, text: &str) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<InlinePayloadRecord>("inline_payloads");
+
+        collection
+            .update_one(
+                doc! { "key": key },
+                doc! {
+                    "$setOnInsert": {
+                        "key": key,
+                        "text": text,
+                        "created_at": BsonDateTime::now(),
+                    }
+                },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// A stored secret, None once it is older than retention
+    /// The TTL index removes expired secrets only about once a minute
+    pub async fn find_inline_payload(
+        &self,
+        key: &str,
+        retention: Duration,
+    ) -> Result<Option<String>, mongodb::error::Error> {
+        let collection = self.db.collection::<InlinePayloadRecord>("inline_payloads");
+
+        Ok(collection
+            .find_one(doc! { "key": key, "created_at": { "$gte": created_after(retention) } })
+            .await?
+            .map(|record| record.text))
+    }
+
+    /// Store a whisper for its reveal button, keeping the first copy of a key
+    pub async fn store_whisper(&self, key: &str, whisper: &Whisper) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<WhisperRecord>("whispers");
+        let record = WhisperRecord {
+            key: key.to_string(),
+            whisper: whisper.clone(),
+            created_at: BsonDateTime::now(),
+        };
+
+        collection
+            .update_one(
+                doc! { "key": key },
+                doc! { "$setOnInsert": mongodb::bson::to_document(&record)? },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// A stored whisper, None once it is older than retention
+    pub async fn find_whisper(&self, key: &str, retention: Duration) -> Result<Option<Whisper>, mongodb::error::Error> {
+        let collection = self.db.collection::<WhisperRecord>("whispers");
+
+        Ok(collection
+            .find_one(doc! { "key": key, "created_at": { "$gte": created_after(retention) } })
+            .await?
+            .map(|record| record.whisper))
+    }
+
+    /// Add an item to an album being collected, true if it is the album's first item
+    pub async fn push_media_group_item(
+        &self,
+        media_group_id: &str,
+        message_id: i32,
+        file_id: &str,
+    ) -> Result<bool, mongodb::error::Error> {
+        let collection = self.db.collection::<MediaGroupRecord>("media_groups");
+
+        let result = collection
+            .update_one(
+                doc! { "media_group_id": media_group_id },
+                doc! {
+                    "$setOnInsert": { "created_at": BsonDateTime::now() },
+                    "$addToSet": { "items": [message_id, file_id] },
+                },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(result.upserted_id.is_some())
+    }
+
+    pub async fn find_media_group(&self, media_group_id: &str) -> Result<Option<MediaGroup>, mongodb::error::Error> {
+        let collection = self.db.collection::<MediaGroupRecord>("media_groups");
+
+        Ok(collection.find_one(doc! { "media_group_id": media_group_id }).await?.map(|record| {
+            let mut group = MediaGroup::new(
+                DateTime::from_timestamp_millis(record.created_at.timestamp_millis()).unwrap_or(DateTime::UNIX_EPOCH),
+            );
+            for (message_id, file_id) in &record.items {
+                group.insert(*message_id, file_id);
+            }
+            group
+        }))
+    }
+
+    pub async fn save_keyboard_owner(&self, chat_id: i64, message_id: i32, owner: &User) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<KeyboardOwnerRecord>("keyboard_owners");
+        let record = KeyboardOwnerRecord {
+            chat_id,
+            message_id,
+            owner: owner.clone(),
+            created_at: BsonDateTime::now(),
+        };
+
+        collection
+            .replace_one(doc! { "chat_id": chat_id, "message_id": message_id }, record)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Who a keyboard was sent to, None once it is older than retention
+    pub async fn find_keyboard_owner(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        retention: Duration,
+    ) -> Result<Option<User>, mongodb::error::Error> {
+        let collection = self.db.collection::<KeyboardOwnerRecord>("keyboard_owners");
+
+        Ok(collection
+            .find_one(doc! {
+                "chat_id": chat_id,
+                "message_id": message_id,
+                "created_at": { "$gte": created_after(retention) },
+            })
+            .await?
+            .map(|record| record.owner))
+    }
+
+    pub async fn find_user_emojis(&self, user_id: i64) -> Result<Option<UserEmojisRecord>, mongodb::error::Error> {
+        let collection = self.db.collection::<UserEmojisRecord>("user_emojis");
+
+        collection.find_one(doc! { "user_id": user_id }).await
+    }
+
+    /// Move an emoji to the front of a user's recent list
+    pub async fn push_recent_emoji(&self, user_id: i64, emoji: &str) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<UserEmojisRecord>("user_emojis");
+
+        collection
+            .update_one(doc! { "user_id": user_id }, recent_emoji_update(emoji))
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Pin an emoji, false if it is already pinned or FAVORITES_LIMIT is reached
+    pub async fn add_favorite_emoji(&self, user_id: i64, emoji: &str) -> Result<bool, mongodb::error::Error> {
+        let collection = self.db.collection::<UserEmojisRecord>("user_emojis");
+
+        // Create the record first, the conditional push below can't upsert
+        // without inserting a second record for a user whose list is full
+        collection
+            .update_one(
+                doc! { "user_id": user_id },
+                doc! {
+                    "$setOnInsert": {
+                        "favorites": [],
+                        "recent": [],
+                        "updated_at": BsonDateTime::now(),
+                    }
+                },
+            )
+            .upsert(true)
+            .await?;
+
+        let result = collection
+            .update_one(
+                favorite_slot_filter(user_id, emoji),
+                doc! {
+                    "$push": { "favorites": { "$each": [emoji], "$slice": FAVORITES_LIMIT as i64 } },
+                    "$set": { "updated_at": BsonDateTime::now() },
+                },
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Unpin an emoji, false if it wasn't pinned
+    pub async fn remove_favorite_emoji(&self, user_id: i64, emoji: &str) -> Result<bool, mongodb::error::Error> {
+        let collection = self.db.collection::<UserEmojisRecord>("user_emojis");
+
+        let result = collection
+            .update_one(
+                doc! { "user_id": user_id, "favorites": emoji },
+                doc! {
+                    "$pull": { "favorites": emoji },
+                    "$set": { "updated_at": BsonDateTime::now() },
+                },
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    pub async fn clear_favorite_emojis(&self, user_id: i64) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<UserEmojisRecord>("user_emojis");
+
+        collection
+            .update_one(
+                doc! { "user_id": user_id },
+                doc! { "$set": { "favorites": [], "updated_at": BsonDateTime::now() } },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_chat_settings(&self, chat_id: i64) -> Result<Option<ChatSettingsRecord>, mongodb::error::Error> {
+        let collection = self.db.collection::<ChatSettingsRecord>("chat_settings");
+
+        collection.find_one(doc! { "chat_id": chat_id }).await
+    }
+
+    pub async fn save_chat_settings(&self, chat_id: i64, admins_can_press: bool) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<ChatSettingsRecord>("chat_settings");
+
+        collection
+            .update_one(
+                doc! { "chat_id": chat_id },
+                doc! {
+                    "$set": {
+                        "admins_can_press": admins_can_press,
+                        "updated_at": BsonDateTime::now(),
+                    }
+                },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_user_state(&self, key: &StateKey) -> Result<Option<PendingState>, mongodb::error::Error> {
+        let collection = self.db.collection::<UserStateRecord>("user_states");
+
+        Ok(collection
+            .find_one(state_filter(key))
+            .await?
+            .and_then(UserStateRecord::into_pending))
+    }
+
+    pub async fn save_user_state(&self, key: &StateKey, pending: &PendingState) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<UserStateRecord>("user_states");
+
+        collection
+            .replace_one(
+                state_filter(key),
+                UserStateRecord {
+                    chat_id: Some(key.chat_id),
+                    user_id: key.user_id,
+                    thread_id: key.thread_id,
+                    state: pending.state.clone(),
+                    created_at: Some(BsonDateTime::from_millis(pending.created_at.timestamp_millis())),
+                    expires_at: Some(BsonDateTime::from_millis(pending.expires_at.timestamp_millis())),
+                    updated_at: BsonDateTime::now(),
+                },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_user_state(&self, key: &StateKey) -> Result<Option<PendingState>, mongodb::error::Error> {
+        let collection = self.db.collection::<UserStateRecord>("user_states");
+
+        Ok(collection
+            .find_one_and_delete(state_filter(key))
+            .await?
+            .and_then(UserStateRecord::into_pending))
+    }
+
+    /// Delete the states that expired by now, returning them with their keys
+    pub async fn delete_expired_user_states(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(StateKey, PendingState)>, mongodb::error::Error> {
+        let collection = self.db.collection::<UserStateRecord>("user_states");
+        let expired = doc! {
+            "$or": [
+                { "expires_at": { "$lte": BsonDateTime::from_millis(now.timestamp_millis()) } },
+                { "expires_at": null },
+            ]
+        };
+
+        let mut cursor = collection.find(expired.clone()).await?;
+        let mut records = Vec::new();
+        while cursor.advance().await? {
+            records.push(cursor.deserialize_current()?);
+        }
+        // Only delete what was read, a replica may have started a new flow meanwhile
+        for record in &records {
+            collection
+                .delete_one(doc! { "$and": [expired.clone(), state_filter(&record.key())] })
+                .await?;
+        }
+
+        Ok(records
+            .into_iter()
+            .filter_map(|record| Some((record.key(), record.into_pending()?)))
+            .collect())
+    }
+
+    /// Scope states saved by user id alone to the user's private chat, where they were started
+    /// States saved before expiry existed are long stale, they are dropped without notice
+    pub async fn migrate_user_states(&self) -> Result<(), mongodb::error::Error> {
+        let collection = self.db.collection::<UserStateRecord>("user_states");
+
+        let result = collection.delete_many(doc! { "expires_at": null }).await?;
+        if result.deleted_count > 0 {
+            log::info!("Dropped {} stored states from before expiry", result.deleted_count);
+        }
+
+        let result = collection
+            .update_many(
+                doc! { "chat_id": { "$exists": false } },
+                vec![doc! { "$set": { "chat_id": "$user_id", "thread_id": null } }],
+            )
+            .await?;
+        if result.modified_count > 0 {
+            log::info!("Scoped {} stored states to private chats", result.modified_count);
+        }
+
+        Ok(())
+    }
+
     pub async fn get_stats(&self) -> Result<Stats, mongodb::error::Error> {
         let collection = self.db.collection::<ChatRecord>("chats");
 
@@ -89,3 +743,36 @@ pub struct Stats {
     pub groups: u64,
     pub channels: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_emoji_update_trims_to_limit() {
+        let update = recent_emoji_update("🔥");
+        let recent = update[0].get_document("$set").unwrap().get_document("recent").unwrap();
+        let slice = recent.get_array("$slice").unwrap();
+
+        assert_eq!(slice[1], (RECENT_LIMIT as i64).into());
+        let concat = slice[0].as_document().unwrap().get_array("$concatArrays").unwrap();
+        assert_eq!(concat[0], mongodb::bson::bson!(["🔥"]));
+        // The older copy is filtered out before the new one goes in front
+        assert_eq!(
+            concat[1].as_document().unwrap().get_document("$filter").unwrap().get_document("cond").unwrap(),
+            &doc! { "$ne": ["$$this", "🔥"] }
+        );
+    }
+
+    #[test]
+    fn test_favorite_slot_filter_stops_at_limit() {
+        assert_eq!(
+            favorite_slot_filter(7, "🔥"),
+            doc! {
+                "user_id": 7_i64,
+                "favorites": { "$ne": "🔥" },
+                format!("favorites.{}", FAVORITES_LIMIT - 1): { "$exists": false },
+            }
+        );
+    }
+}
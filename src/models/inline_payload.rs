@@ -0,0 +1,233 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::Rng;
+use tokio::sync::RwLock;
+use crate::models::{DbClient, Whisper};
+
+/// How long a secret from a picked result stays revealable, in memory and in MongoDB
+pub const PAYLOAD_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a secret offered in an inline answer waits to be picked
+/// A little longer than Telegram caches inline answers, see INLINE_CACHE_TIME
+const STAGED_RETENTION: Duration = Duration::from_secs(10 * 60);
+/// Most secrets kept waiting to be picked, the oldest are dropped first
+const STAGED_LIMIT: usize = 10_000;
+/// Most picked secrets kept in memory when no database is connected
+const CHOSEN_LIMIT: usize = 100_000;
+/// Random bytes in a payload key, 16 characters once encoded
+const PAYLOAD_KEY_BYTES: usize = 12;
+
+/// A secret sent through inline mode, kept so the "🔓 Reveal" button can show it
+/// Callback data is limited to 64 bytes, so the button only carries the key
+///
+/// Every inline answer offers its secret under a new key, kept in memory only
+/// and briefly. Once the user picks a result the secret is persisted (or kept
+/// in memory for PAYLOAD_RETENTION without a database), so keystrokes that are
+/// never sent don't reach the database or linger.
+#[derive(Debug, Clone)]
+pub struct InlinePayload {
+    pub secret: InlineSecret,
+    pub created_at: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub enum InlineSecret {
+    /// Anyone can reveal it
+    Text(String),
+    /// Only its recipients and author can reveal it
+    Whisper(Whisper),
+}
+
+/// Secrets with one retention, so the oldest is always the first to expire
+#[derive(Debug, Default)]
+struct PayloadQueue {
+    payloads: HashMap<String, InlinePayload>,
+    /// Keys oldest first, possibly of secrets already removed
+    order: VecDeque<String>,
+}
+
+impl PayloadQueue {
+    fn insert(&mut self, key: &str, secret: InlineSecret, retention: Duration, limit: usize) {
+        self.remove_expired(retention);
+        while self.payloads.len() >= limit
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.payloads.remove(&oldest);
+        }
+
+        self.order.push_back(key.to_string());
+        self.payloads.insert(
+            key.to_string(),
+            InlinePayload {
+                secret,
+                created_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Drop expired secrets from the front, only ever looking past the ones it removes
+    fn remove_expired(&mut self, retention: Duration) {
+        while let Some(key) = self.order.front() {
+            match self.payloads.get(key) {
+                Some(payload) if payload.created_at.elapsed() < retention => break,
+                _ => {
+                    if let Some(key) = self.order.pop_front() {
+                        self.payloads.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+
+    fn get(&self, key: &str, retention: Duration) -> Option<&InlineSecret> {
+        self.payloads
+            .get(key)
+            .filter(|payload| payload.created_at.elapsed() < retention)
+            .map(|payload| &payload.secret)
+    }
+}
+
+/// Secrets offered in inline answers, and picked ones while no database is connected
+#[derive(Debug, Default)]
+pub struct InlinePayloads {
+    staged: PayloadQueue,
+    chosen: PayloadQueue,
+}
+
+pub type InlinePayloadStorage = Arc<RwLock<InlinePayloads>>;
+
+pub fn create_inline_payload_storage() -> InlinePayloadStorage {
+    Arc::new(RwLock::new(InlinePayloads::default()))
+}
+
+/// Random key for a secret, safe to use in callback data and result ids
+pub fn new_payload_key() -> String {
+    let bytes: [u8; PAYLOAD_KEY_BYTES] = rand::rng().random();
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Remember a secret offered in an inline answer, returning the key for its reveal button
+pub async fn stage_inline_payload(storage: &InlinePayloadStorage, text: &str) -> String {
+    let key = new_payload_key();
+    remember(storage, &key, InlineSecret::Text(text.to_string())).await;
+    key
+}
+
+/// Remember a whisper offered in an inline answer, returning the key for its reveal button
+pub async fn stage_whisper(storage: &InlinePayloadStorage, whisper: &Whisper) -> String {
+    let key = new_payload_key();
+    remember(storage, &key, InlineSecret::Whisper(whisper.clone())).await;
+    key
+}
+
+/// Keep the secret of an inline result the user picked for PAYLOAD_RETENTION
+/// With a database it leaves memory, so every replica reveals the same copy
+pub async fn persist_inline_payload(storage: &InlinePayloadStorage, db: &Option<DbClient>, key: &str) {
+    let Some(secret) = storage.write().await.staged.payloads.remove(key).map(|payload| payload.secret) else {
+        return;
+    };
+
+    if let Some(db) = db {
+        let stored = match &secret {
+            InlineSecret::Text(text) => db.store_inline_payload(key, text).await,
+            InlineSecret::Whisper(whisper) => db.store_whisper(key, whisper).await,
+        };
+        match stored {
+            Ok(()) => return,
+            Err(e) => log::error!("Failed to store inline payload: {}", e),
+        }
+    }
+
+    storage.write().await.chosen.insert(key, secret, PAYLOAD_RETENTION, CHOSEN_LIMIT);
+}
+
+/// Look up a secret by the key from its reveal button
+pub async fn load_inline_payload(storage: &InlinePayloadStorage, db: &Option<DbClient>, key: &str) -> Option<String> {
+    if let Some(InlineSecret::Text(text)) = recall(storage, key).await {
+        return Some(text);
+    }
+
+    match db.as_ref()?.find_inline_payload(key, PAYLOAD_RETENTION).await {
+        Ok(text) => text,
+        Err(e) => {
+            log::error!("Failed to load inline payload: {}", e);
+            None
+        }
+    }
+}
+
+pub async fn load_whisper(storage: &InlinePayloadStorage, db: &Option<DbClient>, key: &str) -> Option<Whisper> {
+    if let Some(InlineSecret::Whisper(whisper)) = recall(storage, key).await {
+        return Some(whisper);
+    }
+
+    match db.as_ref()?.find_whisper(key, PAYLOAD_RETENTION).await {
+        Ok(whisper) => whisper,
+        Err(e) => {
+            log::error!("Failed to load whisper: {}", e);
+            None
+        }
+    }
+}
+
+async fn remember(storage: &InlinePayloadStorage, key: &str, secret: InlineSecret) {
+    storage.write().await.staged.insert(key, secret, STAGED_RETENTION, STAGED_LIMIT);
+}
+
+async fn recall(storage: &InlinePayloadStorage, key: &str) -> Option<InlineSecret> {
+    let payloads = storage.read().await;
+    payloads
+        .chosen
+        .get(key, PAYLOAD_RETENTION)
+        .or_else(|| payloads.staged.get(key, STAGED_RETENTION))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(queue: &PayloadQueue, key: &str) -> Option<String> {
+        match queue.get(key, Duration::MAX) {
+            Some(InlineSecret::Text(text)) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_queue_drops_oldest_over_limit() {
+        let mut queue = PayloadQueue::default();
+        for key in ["a", "b", "c"] {
+            queue.insert(key, InlineSecret::Text(key.to_string()), Duration::MAX, 2);
+        }
+
+        assert_eq!(text(&queue, "a"), None);
+        assert_eq!(text(&queue, "b").as_deref(), Some("b"));
+        assert_eq!(text(&queue, "c").as_deref(), Some("c"));
+        assert_eq!(queue.payloads.len(), 2);
+    }
+
+    #[test]
+    fn test_queue_drops_expired() {
+        let mut queue = PayloadQueue::default();
+        queue.insert("a", InlineSecret::Text("a".to_string()), Duration::MAX, 10);
+        assert!(queue.get("a", Duration::ZERO).is_none());
+
+        // Expired secrets are removed on the next insert
+        queue.insert("b", InlineSecret::Text("b".to_string()), Duration::ZERO, 10);
+        assert_eq!(text(&queue, "a"), None);
+        assert_eq!(queue.order.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_picked_secret_outlives_staging() {
+        let storage = create_inline_payload_storage();
+        let key = stage_inline_payload(&storage, "secret").await;
+        persist_inline_payload(&storage, &None, &key).await;
+
+        let payloads = storage.read().await;
+        assert!(payloads.staged.payloads.is_empty());
+        assert_eq!(text(&payloads.chosen, &key).as_deref(), Some("secret"));
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::types::{ChatId, Message, MessageId, User};
+use tokio::sync::RwLock;
+use crate::models::DbClient;
+
+/// How long the owner of a keyboard is remembered, in memory and in MongoDB
+pub const OWNER_RETENTION: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// The user an emoji keyboard was sent to, only they should press its buttons
+#[derive(Debug, Clone)]
+pub struct KeyboardOwner {
+    pub user: User,
+    pub created_at: Instant,
+}
+
+pub type KeyboardOwnerStorage = Arc<RwLock<HashMap<(ChatId, MessageId), KeyboardOwner>>>;
+
+pub fn create_keyboard_owner_storage() -> KeyboardOwnerStorage {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+/// Remember who a sent keyboard message belongs to
+/// With a database the owner also survives restarts and is shared between replicas
+pub async fn record_keyboard_owner(storage: &KeyboardOwnerStorage, db: &Option<DbClient>, keyboard: &Message, user: &User) {
+    {
+        let mut owners = storage.write().await;
+        owners.retain(|_, owner| owner.created_at.elapsed() < OWNER_RETENTION);
+        owners.insert(
+            (keyboard.chat.id, keyboard.id),
+            KeyboardOwner {
+                user: user.clone(),
+                created_at: Instant::now(),
+            },
+        );
+    }
+
+    if let Some(db) = db
+        && let Err(e) = db.save_keyboard_owner(keyboard.chat.id.0, keyboard.id.0, user).await
+    {
+        log::error!("Failed to store owner of keyboard {} in chat {}: {}", keyboard.id, keyboard.chat.id, e);
+    }
+}
+
+/// Who a keyboard belongs to, None if it was never recorded or has been forgotten
+pub async fn keyboard_owner(storage: &KeyboardOwnerStorage, db: &Option<DbClient>, keyboard: &Message) -> Option<User> {
+    if let Some(owner) = storage.read().await.get(&(keyboard.chat.id, keyboard.id))
+        && owner.created_at.elapsed() < OWNER_RETENTION
+    {
+        return Some(owner.user.clone());
+    }
+
+    let db = db.as_ref()?;
+    match db.find_keyboard_owner(keyboard.chat.id.0, keyboard.id.0, OWNER_RETENTION).await {
+        Ok(owner) => owner,
+        Err(e) => {
+            log::error!("Failed to load owner of keyboard {} in chat {}: {}", keyboard.id, keyboard.chat.id, e);
+            None
+        }
+    }
+}
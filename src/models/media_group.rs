@@ -0,0 +1,81 @@
+use std::time::Duration;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use crate::models::StateStorage;
+
+/// How long to wait for the remaining items of an album after the first one arrives
+pub const MEDIA_GROUP_WINDOW: Duration = Duration::from_millis(1500);
+
+/// How long a collected album stays available for the emoji keyboard
+pub const MEDIA_GROUP_RETENTION: TimeDelta = TimeDelta::hours(1);
+
+/// Files collected from the separate updates of one album (media group)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MediaGroup {
+    /// (message_id, file_id) pairs, kept in message order
+    pub items: Vec<(i32, String)>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl MediaGroup {
+    pub fn new(created_at: DateTime<Utc>) -> Self {
+        Self {
+            items: Vec::new(),
+            created_at,
+        }
+    }
+
+    /// Add an item, ignoring a message that was already added
+    pub fn insert(&mut self, message_id: i32, file_id: &str) {
+        if !self.items.iter().any(|(id, _)| *id == message_id) {
+            self.items.push((message_id, file_id.to_string()));
+            self.items.sort_by_key(|(id, _)| *id);
+        }
+    }
+
+    /// Albums nobody encoded in time
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.created_at + MEDIA_GROUP_RETENTION <= now
+    }
+
+    /// The file_ids of the album in the order they were sent
+    pub fn file_ids(&self) -> Vec<String> {
+        self.items.iter().map(|(_, file_id)| file_id.clone()).collect()
+    }
+
+    /// The message id of the first item, used to reply to the whole album
+    pub fn first_message_id(&self) -> Option<i32> {
+        self.items.first().map(|(message_id, _)| *message_id)
+    }
+}
+
+/// Albums are buffered in the state store, so every replica and restart sees them
+pub type MediaGroupStorage = StateStorage;
+
+/// Add an album item to the buffer
+/// Returns true if this is the first item of the album
+pub async fn push_media_group_item(
+    storage: &MediaGroupStorage,
+    media_group_id: &str,
+    message_id: i32,
+    file_id: &str,
+) -> bool {
+    match storage.push_media_group_item(media_group_id, message_id, file_id).await {
+        Ok(is_first) => is_first,
+        Err(e) => {
+            log::error!("Failed to buffer album {}: {}", media_group_id, e);
+            false
+        }
+    }
+}
+
+/// A buffered album, None if it is unknown, expired or the store can't be read
+pub async fn get_media_group(storage: &MediaGroupStorage, media_group_id: &str) -> Option<MediaGroup> {
+    match storage.get_media_group(media_group_id).await {
+        Ok(group) => group.filter(|group| !group.is_expired(Utc::now())),
+        Err(e) => {
+            log::error!("Failed to load album {}: {}", media_group_id, e);
+            None
+        }
+    }
+}
@@ -1,5 +1,20 @@
 pub mod user_state;
+pub mod state_store;
+pub mod media_group;
 pub mod db;
+pub mod config;
+pub mod inline_payload;
+pub mod whisper;
+pub mod user_emojis;
+pub mod keyboard_owner;
+pub mod chat_settings;
 
-pub use user_state::{BotState, StateStorage, create_state_storage, get_user_state, set_user_state, clear_user_state};
+pub use user_state::{BotState, PendingState, StateKey, StateStorage, create_state_storage, get_user_state, get_pending_state, set_user_state, clear_user_state, sweep_expired_states};
+pub use media_group::{MediaGroupStorage, MEDIA_GROUP_WINDOW, push_media_group_item, get_media_group};
 pub use db::DbClient;
+pub use config::BotConfig;
+pub use inline_payload::{InlinePayloadStorage, PAYLOAD_RETENTION, create_inline_payload_storage, stage_inline_payload, persist_inline_payload, load_inline_payload, stage_whisper, load_whisper};
+pub use whisper::Whisper;
+pub use user_emojis::{UserEmojis, FAVORITES_LIMIT, RECENT_LIMIT, load_user_emojis, record_recent_emoji};
+pub use keyboard_owner::{KeyboardOwnerStorage, OWNER_RETENTION, create_keyboard_owner_storage, record_keyboard_owner, keyboard_owner};
+pub use chat_settings::{load_chat_settings, save_chat_settings};
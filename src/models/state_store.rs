@@ -0,0 +1,430 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::HashMap;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use redb::{Database, ReadableTable, TableDefinition};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::models::{DbClient, PendingState, StateKey};
+use crate::models::media_group::MediaGroup;
+
+/// Conversation states in the file-backed store, by (chat id, user id, topic), as JSON
+const STATES_TABLE: TableDefinition<(i64, i64, Option<i32>), &str> = TableDefinition::new("conversation_states");
+/// States from before they were scoped by chat, by user id, moved on open
+const LEGACY_STATES_TABLE: TableDefinition<i64, &str> = TableDefinition::new("user_states");
+/// Albums being collected, by media group id, as JSON
+const MEDIA_GROUPS_TABLE: TableDefinition<&str, &str> = TableDefinition::new("media_groups");
+
+#[derive(Error, Debug)]
+pub enum StateStoreError {
+    #[error("Database error: {0}")]
+    Database(#[from] mongodb::error::Error),
+    #[error("State file error: {0}")]
+    File(Box<redb::Error>),
+    #[error("Invalid stored state: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("State file task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// redb's errors are large, keep them out of the common path
+fn file_error(e: impl Into<redb::Error>) -> StateStoreError {
+    StateStoreError::File(Box::new(e.into()))
+}
+
+/// Where pending conversation states live, see BotConfig::state_backend
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    /// A user's state, even if it has expired
+    async fn get(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError>;
+    async fn set(&self, key: &StateKey, state: &PendingState) -> Result<(), StateStoreError>;
+    /// Forget a user's state, returning what was stored
+    async fn remove(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError>;
+    /// Forget every state that expired by now, returning them
+    async fn remove_expired(&self, now: DateTime<Utc>) -> Result<Vec<(StateKey, PendingState)>, StateStoreError>;
+    /// Add an item to the album being collected, true if it is the album's first item
+    async fn push_media_group_item(&self, media_group_id: &str, message_id: i32, file_id: &str) -> Result<bool, StateStoreError>;
+    /// An album being collected, even if it has expired
+    async fn get_media_group(&self, media_group_id: &str) -> Result<Option<MediaGroup>, StateStoreError>;
+}
+
+/// Lost on restart and not shared between replicas
+#[derive(Default)]
+pub struct MemoryStateStore {
+    states: RwLock<HashMap<StateKey, PendingState>>,
+    media_groups: RwLock<HashMap<String, MediaGroup>>,
+}
+
+#[async_trait]
+impl StateStore for MemoryStateStore {
+    async fn get(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError> {
+        Ok(self.states.read().await.get(key).cloned())
+    }
+
+    async fn set(&self, key: &StateKey, state: &PendingState) -> Result<(), StateStoreError> {
+        self.states.write().await.insert(*key, state.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError> {
+        Ok(self.states.write().await.remove(key))
+    }
+
+    async fn remove_expired(&self, now: DateTime<Utc>) -> Result<Vec<(StateKey, PendingState)>, StateStoreError> {
+        Ok(self
+            .states
+            .write()
+            .await
+            .extract_if(|_, state| state.is_expired(now))
+            .collect())
+    }
+
+    async fn push_media_group_item(&self, media_group_id: &str, message_id: i32, file_id: &str) -> Result<bool, StateStoreError> {
+        let now = Utc::now();
+        let mut groups = self.media_groups.write().await;
+        groups.retain(|_, group| !group.is_expired(now));
+
+        let is_first = !groups.contains_key(media_group_id);
+        groups
+            .entry(media_group_id.to_string())
+            .or_insert_with(|| MediaGroup::new(now))
+            .insert(message_id, file_id);
+        Ok(is_first)
+    }
+
+    async fn get_media_group(&self, media_group_id: &str) -> Result<Option<MediaGroup>, StateStoreError> {
+        Ok(self.media_groups.read().await.get(media_group_id).cloned())
+    }
+}
+
+/// Shared by every replica connected to the same database
+pub struct MongoStateStore {
+    db: DbClient,
+}
+
+impl MongoStateStore {
+    pub fn new(db: DbClient) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl StateStore for MongoStateStore {
+    async fn get(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError> {
+        Ok(self.db.find_user_state(key).await?)
+    }
+
+    async fn set(&self, key: &StateKey, state: &PendingState) -> Result<(), StateStoreError> {
+        Ok(self.db.save_user_state(key, state).await?)
+    }
+
+    async fn remove(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError> {
+        Ok(self.db.delete_user_state(key).await?)
+    }
+
+    async fn remove_expired(&self, now: DateTime<Utc>) -> Result<Vec<(StateKey, PendingState)>, StateStoreError> {
+        Ok(self.db.delete_expired_user_states(now).await?)
+    }
+
+    async fn push_media_group_item(&self, media_group_id: &str, message_id: i32, file_id: &str) -> Result<bool, StateStoreError> {
+        Ok(self.db.push_media_group_item(media_group_id, message_id, file_id).await?)
+    }
+
+    async fn get_media_group(&self, media_group_id: &str) -> Result<Option<MediaGroup>, StateStoreError> {
+        Ok(self.db.find_media_group(media_group_id).await?)
+    }
+}
+
+/// Survives restarts of a single instance without a database
+pub struct FileStateStore {
+    db: Arc<Database>,
+}
+
+impl FileStateStore {
+    pub fn open(path: &Path) -> Result<Self, StateStoreError> {
+        let db = Database::create(path).map_err(file_error)?;
+
+        // Create the table up front so reads never find it missing, and move
+        // states saved by user id alone to the user's private chat
+        // Those saved before expiry existed are long stale and dropped without notice
+        let txn = db.begin_write().map_err(file_error)?;
+        {
+            txn.open_table(MEDIA_GROUPS_TABLE).map_err(file_error)?;
+            let mut states = txn.open_table(STATES_TABLE).map_err(file_error)?;
+            let legacy = txn.open_table(LEGACY_STATES_TABLE).map_err(file_error)?;
+            for entry in legacy.iter().map_err(file_error)? {
+                let (user_id, json) = entry.map_err(file_error)?;
+                if parse_state(json.value()).is_err() {
+                    continue;
+                }
+                states
+                    .insert(file_key(&StateKey::private(user_id.value())), json.value())
+                    .map_err(file_error)?;
+            }
+        }
+        txn.delete_table(LEGACY_STATES_TABLE).map_err(file_error)?;
+        txn.commit().map_err(file_error)?;
+
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Run a blocking transaction off the async runtime
+    async fn with_db<T, F>(&self, f: F) -> Result<T, StateStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, StateStoreError> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || f(&db)).await?
+    }
+}
+
+fn file_key(key: &StateKey) -> (i64, i64, Option<i32>) {
+    (key.chat_id, key.user_id, key.thread_id)
+}
+
+fn parse_state(json: &str) -> Result<PendingState, StateStoreError> {
+    Ok(serde_json::from_str(json)?)
+}
+
+#[async_trait]
+impl StateStore for FileStateStore {
+    async fn get(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError> {
+        let key = file_key(key);
+        let json = self
+            .with_db(move |db| {
+                let txn = db.begin_read().map_err(file_error)?;
+                let table = txn.open_table(STATES_TABLE).map_err(file_error)?;
+                let json = table.get(key).map_err(file_error)?;
+                Ok(json.map(|json| json.value().to_string()))
+            })
+            .await?;
+
+        json.map(|json| parse_state(&json)).transpose()
+    }
+
+    async fn set(&self, key: &StateKey, state: &PendingState) -> Result<(), StateStoreError> {
+        let key = file_key(key);
+        let json = serde_json::to_string(state)?;
+
+        self.with_db(move |db| {
+            let txn = db.begin_write().map_err(file_error)?;
+            txn.open_table(STATES_TABLE)
+                .map_err(file_error)?
+                .insert(key, json.as_str())
+                .map_err(file_error)?;
+            txn.commit().map_err(file_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove(&self, key: &StateKey) -> Result<Option<PendingState>, StateStoreError> {
+        let key = file_key(key);
+        let json = self
+            .with_db(move |db| {
+                let txn = db.begin_write().map_err(file_error)?;
+                let json = txn
+                    .open_table(STATES_TABLE)
+                    .map_err(file_error)?
+                    .remove(key)
+                    .map_err(file_error)?
+                    .map(|json| json.value().to_string());
+                txn.commit().map_err(file_error)?;
+                Ok(json)
+            })
+            .await?;
+
+        // The state is gone either way, an unreadable one just can't be returned
+        Ok(json.and_then(|json| parse_state(&json).ok()))
+    }
+
+    async fn remove_expired(&self, now: DateTime<Utc>) -> Result<Vec<(StateKey, PendingState)>, StateStoreError> {
+        self.with_db(move |db| {
+            let txn = db.begin_write().map_err(file_error)?;
+            let mut expired = Vec::new();
+            {
+                let mut table = txn.open_table(STATES_TABLE).map_err(file_error)?;
+                let mut stale_keys = Vec::new();
+                for entry in table.iter().map_err(file_error)? {
+                    let (key, json) = entry.map_err(file_error)?;
+                    let (chat_id, user_id, thread_id) = key.value();
+                    let key = StateKey { chat_id, user_id, thread_id };
+                    match parse_state(json.value()) {
+                        Ok(pending) if pending.is_expired(now) => expired.push((key, pending)),
+                        Ok(_) => continue,
+                        Err(e) => log::warn!("Dropping unreadable state of {}: {}", key, e),
+                    }
+                    stale_keys.push(key);
+                }
+                for key in stale_keys {
+                    table.remove(file_key(&key)).map_err(file_error)?;
+                }
+            }
+            txn.commit().map_err(file_error)?;
+            Ok(expired)
+        })
+        .await
+    }
+
+    async fn push_media_group_item(&self, media_group_id: &str, message_id: i32, file_id: &str) -> Result<bool, StateStoreError> {
+        let media_group_id = media_group_id.to_string();
+        let file_id = file_id.to_string();
+
+        self.with_db(move |db| {
+            let now = Utc::now();
+            let txn = db.begin_write().map_err(file_error)?;
+            let is_first;
+            {
+                let mut table = txn.open_table(MEDIA_GROUPS_TABLE).map_err(file_error)?;
+
+                // Drop albums nobody encoded in time, and unreadable ones
+                let mut stale_ids = Vec::new();
+                for entry in table.iter().map_err(file_error)? {
+                    let (id, json) = entry.map_err(file_error)?;
+                    match serde_json::from_str::<MediaGroup>(json.value()) {
+                        Ok(group) if !group.is_expired(now) => {}
+                        _ => stale_ids.push(id.value().to_string()),
+                    }
+                }
+                for id in stale_ids {
+                    table.remove(id.as_str()).map_err(file_error)?;
+                }
+
+                let existing = table.get(media_group_id.as_str()).map_err(file_error)?.map(|json| json.value().to_string());
+                is_first = existing.is_none();
+                let mut group = match existing {
+                    Some(json) => serde_json::from_str(&json)?,
+                    None => MediaGroup::new(now),
+                };
+                group.insert(message_id, &file_id);
+                table
+                    .insert(media_group_id.as_str(), serde_json::to_string(&group)?.as_str())
+                    .map_err(file_error)?;
+            }
+            txn.commit().map_err(file_error)?;
+            Ok(is_first)
+        })
+        .await
+    }
+
+    async fn get_media_group(&self, media_group_id: &str) -> Result<Option<MediaGroup>, StateStoreError> {
+        let media_group_id = media_group_id.to_string();
+        let json = self
+            .with_db(move |db| {
+                let txn = db.begin_read().map_err(file_error)?;
+                let table = txn.open_table(MEDIA_GROUPS_TABLE).map_err(file_error)?;
+                let json = table.get(media_group_id.as_str()).map_err(file_error)?;
+                Ok(json.map(|json| json.value().to_string()))
+            })
+            .await?;
+
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::BotState;
+
+    fn text_state(text: &str) -> PendingState {
+        PendingState::new(BotState::AwaitingCustomEmoji { text: text.to_string() })
+    }
+
+    fn text_of(pending: Option<PendingState>) -> Option<String> {
+        match pending?.state {
+            BotState::AwaitingCustomEmoji { text } => Some(text),
+            _ => None,
+        }
+    }
+
+    /// set, get and remove behave the same on every backend
+    async fn check_round_trip(store: &dyn StateStore) {
+        let key = StateKey { chat_id: -100, user_id: 7, thread_id: Some(3) };
+
+        assert!(store.get(&key).await.unwrap().is_none());
+        store.set(&key, &text_state("first")).await.unwrap();
+        store.set(&key, &text_state("second")).await.unwrap();
+        assert_eq!(text_of(store.get(&key).await.unwrap()), Some("second".to_string()));
+
+        assert_eq!(text_of(store.remove(&key).await.unwrap()), Some("second".to_string()));
+        assert!(store.get(&key).await.unwrap().is_none());
+        assert!(store.remove(&key).await.unwrap().is_none());
+    }
+
+    /// Album items arrive in any order and sometimes twice
+    async fn check_media_groups(store: &dyn StateStore) {
+        assert!(store.get_media_group("album").await.unwrap().is_none());
+        assert!(store.push_media_group_item("album", 11, "second").await.unwrap());
+        assert!(!store.push_media_group_item("album", 10, "first").await.unwrap());
+        assert!(!store.push_media_group_item("album", 11, "second").await.unwrap());
+        assert!(store.push_media_group_item("other", 20, "other").await.unwrap());
+
+        let group = store.get_media_group("album").await.unwrap().unwrap();
+        assert_eq!(group.file_ids(), ["first", "second"]);
+        assert_eq!(group.first_message_id(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_memory_round_trip() {
+        check_round_trip(&MemoryStateStore::default()).await;
+        check_media_groups(&MemoryStateStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStateStore::open(&dir.path().join("states.redb")).unwrap();
+        check_round_trip(&store).await;
+        check_media_groups(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("states.redb");
+        let key = StateKey::private(7);
+
+        let store = FileStateStore::open(&path).unwrap();
+        store.set(&key, &text_state("kept")).await.unwrap();
+        store.push_media_group_item("album", 10, "first").await.unwrap();
+        drop(store);
+
+        let store = FileStateStore::open(&path).unwrap();
+        assert_eq!(text_of(store.get(&key).await.unwrap()), Some("kept".to_string()));
+        assert!(!store.push_media_group_item("album", 11, "second").await.unwrap());
+        assert_eq!(store.get_media_group("album").await.unwrap().unwrap().file_ids(), ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_file_migrates_legacy_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("states.redb");
+
+        // States saved by user id alone, one from before expiry existed
+        let stale = serde_json::to_string(&BotState::AwaitingCustomEmoji { text: "old".to_string() }).unwrap();
+        let current = serde_json::to_string(&text_state("new")).unwrap();
+        let db = Database::create(&path).unwrap();
+        let txn = db.begin_write().unwrap();
+        {
+            let mut table = txn.open_table(LEGACY_STATES_TABLE).unwrap();
+            table.insert(7, stale.as_str()).unwrap();
+            table.insert(8, current.as_str()).unwrap();
+        }
+        txn.commit().unwrap();
+        drop(db);
+
+        // The stale state is dropped without ever reaching the sweeper
+        let store = FileStateStore::open(&path).unwrap();
+        assert!(store.get(&StateKey::private(7)).await.unwrap().is_none());
+        assert!(store.remove_expired(Utc::now()).await.unwrap().is_empty());
+        let moved = store.get(&StateKey::private(8)).await.unwrap();
+        assert_eq!(text_of(moved), Some("new".to_string()));
+
+        // The legacy table is gone, so reopening doesn't move it again
+        let txn = store.db.begin_read().unwrap();
+        assert!(txn.open_table(LEGACY_STATES_TABLE).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use crate::models::DbClient;
+
+/// Number of recently used emojis kept per user
+pub const RECENT_LIMIT: usize = 5;
+/// Number of emojis a user can pin
+pub const FAVORITES_LIMIT: usize = 10;
+
+/// A user's pinned and recently used carrier emojis, most recent first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserEmojis {
+    pub favorites: Vec<String>,
+    pub recent: Vec<String>,
+}
+
+/// A user's emojis, empty when running without a database
+pub async fn load_user_emojis(db: &Option<DbClient>, user_id: i64) -> UserEmojis {
+    let Some(db) = db else {
+        return UserEmojis::default();
+    };
+
+    match db.find_user_emojis(user_id).await {
+        Ok(Some(record)) => UserEmojis {
+            favorites: record.favorites,
+            recent: record.recent,
+        },
+        Ok(None) => UserEmojis::default(),
+        Err(e) => {
+            log::error!("Failed to load emojis of user {}: {}", user_id, e);
+            UserEmojis::default()
+        }
+    }
+}
+
+/// Remember that a user picked an emoji as a carrier
+pub async fn record_recent_emoji(db: &Option<DbClient>, user_id: i64, emoji: &str) {
+    let Some(db) = db else {
+        return;
+    };
+
+    if let Err(e) = db.push_recent_emoji(user_id, emoji).await {
+        log::error!("Failed to save recent emoji of user {}: {}", user_id, e);
+    }
+}
@@ -1,34 +1,280 @@
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::models::config::StateBackend;
+use crate::models::DbClient;
+use crate::models::state_store::{FileStateStore, MemoryStateStore, MongoStateStore, StateStore};
+use crate::utils::{FileType, MessageOrigin, SharedContent};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use teloxide::types::Message;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BotState {
     Idle,
     AwaitingCustomEmoji { text: String },
-    AwaitingFileEmoji { file_id: String, file_type: String },
+    AwaitingFileEmoji {
+        file_id: String,
+        file_type: FileType,
+        #[serde(default)]
+        file_unique_id: String,
+        #[serde(default)]
+        origin: Option<MessageOrigin>,
+    },
+    AwaitingAlbumEmoji { file_ids: Vec<String> },
+    AwaitingContentEmoji { content: SharedContent },
 }
 
-pub type StateStorage = Arc<RwLock<HashMap<i64, BotState>>>;
+impl BotState {
+    /// What the user is hiding, for messages about the pending step
+    pub fn subject(&self) -> &'static str {
+        match self {
+            BotState::Idle => "message",
+            BotState::AwaitingCustomEmoji { .. } => "text",
+            BotState::AwaitingFileEmoji { .. } => "file",
+            BotState::AwaitingAlbumEmoji { .. } => "album",
+            BotState::AwaitingContentEmoji { content } => content.type_name(),
+        }
+    }
+}
+
+/// Whose pending step a state is, and where it happens
+/// A flow started in one chat or forum topic is never completed from another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StateKey {
+    pub chat_id: i64,
+    pub user_id: i64,
+    /// Forum topic, None outside forums
+    pub thread_id: Option<i32>,
+}
+
+impl StateKey {
+    /// The key of a user in the chat and topic of a message
+    pub fn new(msg: &Message, user_id: i64) -> Self {
+        // Non-forum groups set a thread id on replies too, only topics separate flows
+        let thread_id = msg.thread_id.filter(|_| msg.is_topic_message).map(|thread| thread.0.0);
+        Self {
+            chat_id: msg.chat.id.0,
+            user_id,
+            thread_id,
+        }
+    }
+
+    /// Where states saved before they were scoped belong, the user's private chat
+    pub fn private(user_id: i64) -> Self {
+        Self {
+            chat_id: user_id,
+            user_id,
+            thread_id: None,
+        }
+    }
+}
 
-pub fn create_state_storage() -> StateStorage {
-    Arc::new(RwLock::new(HashMap::new()))
+impl std::fmt::Display for StateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "user {} in chat {}", self.user_id, self.chat_id)?;
+        if let Some(thread_id) = self.thread_id {
+            write!(f, " topic {}", thread_id)?;
+        }
+        Ok(())
+    }
 }
 
-pub async fn get_user_state(storage: &StateStorage, user_id: i64) -> BotState {
-    storage
-        .read()
-        .await
-        .get(&user_id)
-        .cloned()
-        .unwrap_or(BotState::Idle)
+/// How long the bot waits for the emoji of a pending step
+pub const STATE_TTL: TimeDelta = TimeDelta::minutes(15);
+
+/// A state as stored, with when it stops applying
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingState {
+    pub state: BotState,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
 }
 
-pub async fn set_user_state(storage: &StateStorage, user_id: i64, state: BotState) {
-    storage.write().await.insert(user_id, state);
+impl PendingState {
+    pub fn new(state: BotState) -> Self {
+        let created_at = Utc::now();
+        Self {
+            state,
+            created_at,
+            expires_at: created_at + STATE_TTL,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
 }
 
-pub async fn clear_user_state(storage: &StateStorage, user_id: i64) {
-    storage.write().await.remove(&user_id);
+pub type StateStorage = Arc<dyn StateStore>;
+
+/// Open the configured state store, falling back to memory if it is unavailable
+pub async fn create_state_storage(backend: &StateBackend, db: &Option<DbClient>) -> StateStorage {
+    match backend {
+        StateBackend::Memory => Arc::new(MemoryStateStore::default()),
+        StateBackend::Mongo => match db {
+            Some(db) => {
+                if let Err(e) = db.migrate_user_states().await {
+                    log::error!("Failed to scope stored states by chat: {}", e);
+                }
+                Arc::new(MongoStateStore::new(db.clone()))
+            }
+            None => {
+                log::warn!("STATE_BACKEND is mongodb but the database is not connected, keeping state in memory");
+                Arc::new(MemoryStateStore::default())
+            }
+        },
+        StateBackend::File(path) => match FileStateStore::open(path) {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                log::error!("Failed to open state file {}, keeping state in memory: {}", path.display(), e);
+                Arc::new(MemoryStateStore::default())
+            }
+        },
+    }
+}
+
+/// A user's state, Idle if there is none, it expired or the store can't be read
+pub async fn get_user_state(storage: &StateStorage, key: &StateKey) -> BotState {
+    match get_pending_state(storage, key).await {
+        Some(pending) if !pending.is_expired(Utc::now()) => pending.state,
+        _ => BotState::Idle,
+    }
+}
+
+/// A user's stored state including expired ones, so the user can be told it lapsed
+pub async fn get_pending_state(storage: &StateStorage, key: &StateKey) -> Option<PendingState> {
+    match storage.get(key).await {
+        Ok(pending) => pending,
+        Err(e) => {
+            log::error!("Failed to load state of {}: {}", key, e);
+            None
+        }
+    }
+}
+
+/// Start waiting for a user, for at most STATE_TTL
+pub async fn set_user_state(storage: &StateStorage, key: &StateKey, state: BotState) {
+    if let Err(e) = storage.set(key, &PendingState::new(state)).await {
+        log::error!("Failed to save state of {}: {}", key, e);
+    }
+}
+
+/// Forget a user's state, returning it if there was one
+pub async fn clear_user_state(storage: &StateStorage, key: &StateKey) -> Option<BotState> {
+    match storage.remove(key).await {
+        Ok(pending) => pending.map(|pending| pending.state),
+        Err(e) => {
+            log::error!("Failed to clear state of {}: {}", key, e);
+            None
+        }
+    }
+}
+
+/// Remove every expired state, returning them so their users can be told
+pub async fn sweep_expired_states(storage: &StateStorage) -> Vec<(StateKey, BotState)> {
+    match storage.remove_expired(Utc::now()).await {
+        Ok(expired) => expired.into_iter().map(|(key, pending)| (key, pending.state)).collect(),
+        Err(e) => {
+            log::error!("Failed to sweep expired states: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_state(text: &str) -> BotState {
+        BotState::AwaitingCustomEmoji { text: text.to_string() }
+    }
+
+    fn text_of(state: BotState) -> Option<String> {
+        match state {
+            BotState::AwaitingCustomEmoji { text } => Some(text),
+            _ => None,
+        }
+    }
+
+    /// A message from user 7 in a supergroup, as Telegram sends it
+    fn group_message(thread_id: Option<i32>, is_topic_message: bool) -> Message {
+        let mut message = serde_json::json!({
+            "message_id": 10,
+            "date": 1_700_000_000,
+            "chat": { "id": -1001, "type": "supergroup", "title": "Group", "is_forum": true },
+            "from": { "id": 7, "is_bot": false, "first_name": "Alice" },
+            "text": "hello",
+        });
+        if let Some(thread_id) = thread_id {
+            message["message_thread_id"] = thread_id.into();
+            message["is_topic_message"] = is_topic_message.into();
+        }
+        serde_json::from_value(message).unwrap()
+    }
+
+    #[test]
+    fn test_state_key_scoping() {
+        let key = StateKey::new(&group_message(Some(5), true), 7);
+        assert_eq!(key, StateKey { chat_id: -1001, user_id: 7, thread_id: Some(5) });
+
+        // Replies outside forum topics carry a thread id too, it doesn't split flows
+        assert_eq!(StateKey::new(&group_message(Some(5), false), 7).thread_id, None);
+        assert_eq!(StateKey::new(&group_message(None, false), 7).thread_id, None);
+
+        assert_eq!(StateKey::private(7), StateKey { chat_id: 7, user_id: 7, thread_id: None });
+    }
+
+    #[tokio::test]
+    async fn test_states_are_independent() {
+        let storage: StateStorage = Arc::new(MemoryStateStore::default());
+        let msg = group_message(None, false);
+        let alice = StateKey::new(&msg, 7);
+        let bob = StateKey::new(&msg, 8);
+        let first_topic = StateKey::new(&group_message(Some(5), true), 7);
+        let second_topic = StateKey::new(&group_message(Some(6), true), 7);
+
+        set_user_state(&storage, &alice, text_state("alice")).await;
+        set_user_state(&storage, &first_topic, text_state("first topic")).await;
+        set_user_state(&storage, &second_topic, text_state("second topic")).await;
+
+        assert_eq!(text_of(get_user_state(&storage, &alice).await).as_deref(), Some("alice"));
+        assert!(matches!(get_user_state(&storage, &bob).await, BotState::Idle));
+        assert_eq!(text_of(get_user_state(&storage, &first_topic).await).as_deref(), Some("first topic"));
+
+        clear_user_state(&storage, &second_topic).await;
+        assert!(matches!(get_user_state(&storage, &second_topic).await, BotState::Idle));
+        assert_eq!(text_of(get_user_state(&storage, &first_topic).await).as_deref(), Some("first topic"));
+        assert_eq!(text_of(get_user_state(&storage, &alice).await).as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_sweep_reports_expired_states() {
+        let storage: StateStorage = Arc::new(MemoryStateStore::default());
+        let expired_key = StateKey::private(1);
+        let pending_key = StateKey::private(2);
+
+        let now = Utc::now();
+        let expired = PendingState {
+            state: text_state("late"),
+            created_at: now - STATE_TTL - TimeDelta::seconds(1),
+            expires_at: now - TimeDelta::seconds(1),
+        };
+        storage.set(&expired_key, &expired).await.unwrap();
+        set_user_state(&storage, &pending_key, text_state("on time")).await;
+
+        // Expired states read as Idle even before the sweep removes them
+        assert!(matches!(get_user_state(&storage, &expired_key).await, BotState::Idle));
+        assert!(get_pending_state(&storage, &expired_key).await.is_some());
+
+        let swept = sweep_expired_states(&storage).await;
+        assert_eq!(swept.len(), 1);
+        assert_eq!(swept[0].0, expired_key);
+        assert!(matches!(&swept[0].1, BotState::AwaitingCustomEmoji { text } if text == "late"));
+
+        assert!(get_pending_state(&storage, &expired_key).await.is_none());
+        assert!(matches!(
+            get_user_state(&storage, &pending_key).await,
+            BotState::AwaitingCustomEmoji { text } if text == "on time"
+        ));
+        assert!(sweep_expired_states(&storage).await.is_empty());
+    }
 }
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use teloxide::types::User;
+
+/// A secret sent with `@user1 @user2 secret` in inline mode
+/// Only the listed users and the author can reveal it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Whisper {
+    pub author_id: i64,
+    pub recipient_ids: Vec<i64>,
+    /// Lowercase, without the leading '@'
+    pub recipient_usernames: Vec<String>,
+    pub text: String,
+}
+
+impl Whisper {
+    /// Split `@user1 @user2 secret` into a whisper
+    /// Returns None if the query does not start with a mention or has no text
+    pub fn parse(author_id: i64, query: &str) -> Option<Self> {
+        let mut recipient_ids = Vec::new();
+        let mut recipient_usernames = Vec::new();
+        let mut rest = query.trim_start();
+
+        while let Some(mention) = rest.strip_prefix('@') {
+            let end = mention.find(char::is_whitespace).unwrap_or(mention.len());
+            let name = &mention[..end];
+            if name.is_empty() {
+                break;
+            }
+
+            // @123456789 addresses a user without a username by id
+            match name.parse::<i64>() {
+                Ok(id) => recipient_ids.push(id),
+                Err(_) => recipient_usernames.push(name.to_lowercase()),
+            }
+            rest = mention[end..].trim_start();
+        }
+
+        if (recipient_ids.is_empty() && recipient_usernames.is_empty()) || rest.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            author_id,
+            recipient_ids,
+            recipient_usernames,
+            text: rest.to_string(),
+        })
+    }
+
+    /// The recipients as they were written, e.g. "@alice, @bob"
+    pub fn recipients(&self) -> String {
+        self.recipient_usernames
+            .iter()
+            .map(|name| format!("@{}", name))
+            .chain(self.recipient_ids.iter().map(|id| format!("@{}", id)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn can_read(&self, user: &User) -> bool {
+        let user_id = user.id.0 as i64;
+        user_id == self.author_id
+            || self.recipient_ids.contains(&user_id)
+            || user
+                .username
+                .as_ref()
+                .is_some_and(|name| self.recipient_usernames.contains(&name.to_lowercase()))
+    }
+}
@@ -0,0 +1,134 @@
+//! Callback data of the bot's inline keyboard buttons
+//!
+//! Data is `<version><tag>` followed by `:`-separated fields, e.g. `1e:🔥` or
+//! `1p:3:2`. Telegram rejects buttons with more than 64 bytes of data, so the
+//! length is checked when a button is built rather than when it is pressed.
+
+use thiserror::Error;
+use crate::utils::emojis::EmojiCategory;
+
+/// Telegram's limit on callback data
+pub const MAX_CALLBACK_DATA: usize = 64;
+/// Bumped whenever the meaning of existing data changes, older buttons then count as expired
+const CALLBACK_VERSION: char = '1';
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CallbackDataError {
+    #[error("Callback data is {0} bytes, Telegram allows {MAX_CALLBACK_DATA}")]
+    TooLong(usize),
+    #[error("Unsupported callback data: {0}")]
+    Unsupported(String),
+}
+
+/// What pressing a button does
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallbackAction {
+    /// Hide the pending message in this emoji
+    Encode(String),
+    Random,
+    /// Ask for a custom emoji
+    Custom,
+    /// Show a page of the emoji picker
+    Pick { category: EmojiCategory, page: usize },
+    /// A label-only button, like the page number
+    Noop,
+    /// Show an inline secret, by its payload key
+    Reveal(String),
+    /// Show a whisper to its recipients, by its key
+    Whisper(String),
+}
+
+impl CallbackAction {
+    /// Serialize for a button, failing if Telegram would reject it
+    pub fn to_data(&self) -> Result<String, CallbackDataError> {
+        let body = match self {
+            CallbackAction::Encode(emoji) => format!("e:{}", emoji),
+            CallbackAction::Random => "r".to_string(),
+            CallbackAction::Custom => "c".to_string(),
+            CallbackAction::Pick { category, page } => format!("p:{}:{}", category.index(), page),
+            CallbackAction::Noop => "n".to_string(),
+            CallbackAction::Reveal(key) => format!("v:{}", key),
+            CallbackAction::Whisper(key) => format!("w:{}", key),
+        };
+        let data = format!("{}{}", CALLBACK_VERSION, body);
+
+        if data.len() > MAX_CALLBACK_DATA {
+            return Err(CallbackDataError::TooLong(data.len()));
+        }
+        Ok(data)
+    }
+
+    pub fn parse(data: &str) -> Result<Self, CallbackDataError> {
+        let unsupported = || CallbackDataError::Unsupported(data.to_string());
+
+        let Some(body) = data.strip_prefix(CALLBACK_VERSION) else {
+            return Self::parse_legacy(data).ok_or_else(unsupported);
+        };
+        let (tag, fields) = body.split_once(':').unwrap_or((body, ""));
+
+        let action = match (tag, fields) {
+            ("e", emoji) if !emoji.is_empty() => CallbackAction::Encode(emoji.to_string()),
+            ("r", "") => CallbackAction::Random,
+            ("c", "") => CallbackAction::Custom,
+            ("p", fields) => {
+                let (category, page) = fields.split_once(':').ok_or_else(unsupported)?;
+                CallbackAction::Pick {
+                    category: category.parse().ok().and_then(EmojiCategory::from_index).ok_or_else(unsupported)?,
+                    page: page.parse().map_err(|_| unsupported())?,
+                }
+            }
+            ("n", "") => CallbackAction::Noop,
+            ("v", key) if !key.is_empty() => CallbackAction::Reveal(key.to_string()),
+            ("w", key) if !key.is_empty() => CallbackAction::Whisper(key.to_string()),
+            _ => return Err(unsupported()),
+        };
+        Ok(action)
+    }
+
+    /// Reveal buttons sent through inline mode before versioning live on in other chats
+    fn parse_legacy(data: &str) -> Option<Self> {
+        match data.split_once(':')? {
+            ("reveal", key) if !key.is_empty() => Some(CallbackAction::Reveal(key.to_string())),
+            ("whisper", key) if !key.is_empty() => Some(CallbackAction::Whisper(key.to_string())),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let actions = [
+            CallbackAction::Encode("👨‍👩‍👧‍👦".to_string()),
+            CallbackAction::Random,
+            CallbackAction::Custom,
+            CallbackAction::Pick { category: EmojiCategory::Flags, page: 12 },
+            CallbackAction::Noop,
+            CallbackAction::Reveal("0123456789abcdef".to_string()),
+            CallbackAction::Whisper("fedcba9876543210".to_string()),
+        ];
+
+        for action in actions {
+            let data = action.to_data().unwrap();
+            assert_eq!(CallbackAction::parse(&data).unwrap(), action);
+        }
+    }
+
+    #[test]
+    fn test_length_limit() {
+        let long = CallbackAction::Encode("🏴󠁧󠁢󠁳󠁣󠁴󠁿".repeat(3));
+        assert!(matches!(long.to_data(), Err(CallbackDataError::TooLong(_))));
+    }
+
+    #[test]
+    fn test_legacy_and_unknown_data() {
+        assert_eq!(CallbackAction::parse("reveal:abc").unwrap(), CallbackAction::Reveal("abc".to_string()));
+        assert!(CallbackAction::parse("encode:🔥").is_err());
+        assert!(CallbackAction::parse("1x").is_err());
+        assert!(CallbackAction::parse("1p:99:0").is_err());
+        assert!(CallbackAction::parse("2r").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Small LZSS compressor for hidden text
+//!
+//! Every hidden byte costs up to four bytes of variation selectors, so
+//! repetitive secrets are worth compressing. The format is a sequence of
+//! groups: a control byte whose bits (lowest first) tell whether each of the
+//! next eight items is a literal byte or a back reference. A back reference is
+//! two bytes: a 12-bit distance (1..=4096) and a 4-bit length (3..=18).
+
+use thiserror::Error;
+
+const WINDOW_SIZE: usize = 4096;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 18;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CompressionError {
+    #[error("Back reference points before the start of the data")]
+    InvalidReference,
+    #[error("Compressed data is truncated")]
+    Truncated,
+}
+
+/// Compress bytes, the output may be larger than the input for random data
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut control_pos = 0;
+    let mut item = 8;
+    let mut pos = 0;
+
+    while pos < data.len() {
+        if item == 8 {
+            control_pos = output.len();
+            output.push(0);
+            item = 0;
+        }
+
+        let (distance, length) = longest_match(data, pos);
+        if length >= MIN_MATCH {
+            output[control_pos] |= 1 << item;
+            let packed = ((distance - 1) << 4) | (length - MIN_MATCH);
+            output.extend((packed as u16).to_le_bytes());
+            pos += length;
+        } else {
+            output.push(data[pos]);
+            pos += 1;
+        }
+        item += 1;
+    }
+
+    output
+}
+
+/// Inverse of compress
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    let mut output = Vec::new();
+    let mut bytes = data.iter().copied();
+
+    while let Some(control) = bytes.next() {
+        for item in 0..8 {
+            if control & (1 << item) == 0 {
+                match bytes.next() {
+                    Some(byte) => output.push(byte),
+                    None => return Ok(output),
+                }
+                continue;
+            }
+
+            let (Some(low), Some(high)) = (bytes.next(), bytes.next()) else {
+                return Err(CompressionError::Truncated);
+            };
+            let packed = u16::from_le_bytes([low, high]) as usize;
+            let distance = (packed >> 4) + 1;
+            let length = (packed & 0xF) + MIN_MATCH;
+
+            let start = output.len().checked_sub(distance).ok_or(CompressionError::InvalidReference)?;
+            // Byte by byte, a reference may overlap the bytes it produces
+            for i in 0..length {
+                output.push(output[start + i]);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Longest earlier occurrence of the bytes at pos, as (distance, length)
+fn longest_match(data: &[u8], pos: usize) -> (usize, usize) {
+    let max_length = MAX_MATCH.min(data.len() - pos);
+    let mut best = (0, 0);
+
+    for start in pos.saturating_sub(WINDOW_SIZE)..pos {
+        let length = (0..max_length)
+            .take_while(|&i| data[start + i] == data[pos + i])
+            .count();
+        if length > best.1 {
+            best = (pos - start, length);
+            if length == max_length {
+                break;
+            }
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_repetitive_text_shrinks() {
+        let text = "ha".repeat(200);
+        let compressed = compress(text.as_bytes());
+
+        assert!(compressed.len() < text.len() / 4);
+        assert_eq!(decompress(&compressed).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn test_invalid_reference() {
+        // A back reference as the very first item
+        assert_eq!(decompress(&[1, 0, 0]), Err(CompressionError::InvalidReference));
+        assert_eq!(decompress(&[1, 0]), Err(CompressionError::Truncated));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_compress_round_trip(data in proptest::collection::vec(prop_oneof![Just(b'a'), any::<u8>()], 0..2000)) {
+            prop_assert_eq!(decompress(&compress(&data)).unwrap(), data);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use unicode_segmentation::UnicodeSegmentation;
+
 pub const EMOJI_LIST: &[&str] = &[
     "😀", "😂", "🥰", "😎", "🤔", "👍", "👎", "👏",
     "😅", "🤝", "🎉", "🎂", "🍕", "❤️", "🌞", "🌙",
@@ -11,3 +13,228 @@ pub fn get_random_emoji() -> &'static str {
     let idx = rng.random_range(0..EMOJI_LIST.len());
     EMOJI_LIST[idx]
 }
+
+/// Tabs of the emoji picker keyboard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmojiCategory {
+    /// The preset EMOJI_LIST
+    Popular,
+    Smileys,
+    People,
+    Food,
+    Nature,
+    Symbols,
+    Flags,
+}
+
+impl EmojiCategory {
+    pub const ALL: [EmojiCategory; 7] = [
+        EmojiCategory::Popular,
+        EmojiCategory::Smileys,
+        EmojiCategory::People,
+        EmojiCategory::Food,
+        EmojiCategory::Nature,
+        EmojiCategory::Symbols,
+        EmojiCategory::Flags,
+    ];
+
+    /// Position of the tab, used in callback data
+    pub fn index(&self) -> usize {
+        Self::ALL.iter().position(|category| category == self).unwrap_or(0)
+    }
+
+    pub fn from_index(index: usize) -> Option<Self> {
+        Self::ALL.get(index).copied()
+    }
+
+    /// Label of the category's tab
+    pub fn icon(&self) -> &'static str {
+        match self {
+            EmojiCategory::Popular => "⭐",
+            EmojiCategory::Smileys => "😀",
+            EmojiCategory::People => "👋",
+            EmojiCategory::Food => "🍕",
+            EmojiCategory::Nature => "🌿",
+            EmojiCategory::Symbols => "🔣",
+            EmojiCategory::Flags => "🏁",
+        }
+    }
+
+    pub fn emojis(&self) -> Vec<&'static str> {
+        let group = match self {
+            EmojiCategory::Popular => return EMOJI_LIST.to_vec(),
+            EmojiCategory::Smileys => emojis::Group::SmileysAndEmotion,
+            EmojiCategory::People => emojis::Group::PeopleAndBody,
+            EmojiCategory::Food => emojis::Group::FoodAndDrink,
+            EmojiCategory::Nature => emojis::Group::AnimalsAndNature,
+            EmojiCategory::Symbols => emojis::Group::Symbols,
+            EmojiCategory::Flags => emojis::Group::Flags,
+        };
+        group.emojis().map(|emoji| emoji.as_str()).collect()
+    }
+}
+
+/// Every emoji that can carry a secret: the presets first, then the full Unicode catalog
+pub fn emoji_catalog() -> impl Iterator<Item = &'static str> {
+    EMOJI_LIST.iter().copied().chain(
+        emojis::iter()
+            .map(|emoji| emoji.as_str())
+            .filter(|emoji| !EMOJI_LIST.contains(emoji)),
+    )
+}
+
+/// Emojis matching a keyword by shortcode or name, best matches first
+pub fn search_emojis(keyword: &str) -> Vec<&'static str> {
+    let keyword = keyword.trim().trim_matches(':').to_lowercase().replace('_', " ");
+    if keyword.is_empty() {
+        return Vec::new();
+    }
+
+    let mut matches: Vec<(u8, &'static str)> = emoji_catalog()
+        .filter_map(|emoji| Some((keyword_rank(emojis::get(emoji)?, &keyword)?, emoji)))
+        .collect();
+    // Stable, so equally good matches keep the catalog order
+    matches.sort_by_key(|(rank, _)| *rank);
+
+    matches.into_iter().map(|(_, emoji)| emoji).collect()
+}
+
+/// Emoji whose shortcode or name is exactly the keyword
+pub fn find_emoji(keyword: &str) -> Option<&'static str> {
+    let best = *search_emojis(keyword).first()?;
+    let keyword = keyword.trim().trim_matches(':').to_lowercase().replace('_', " ");
+
+    (keyword_rank(emojis::get(best)?, &keyword) == Some(0)).then_some(best)
+}
+
+/// How well an emoji matches a keyword: exact, a word prefix or anywhere, None if not at all
+fn keyword_rank(emoji: &emojis::Emoji, keyword: &str) -> Option<u8> {
+    let name = emoji.name().to_lowercase();
+    let shortcodes: Vec<String> = emoji.shortcodes().map(|code| code.replace('_', " ")).collect();
+    let labels = || std::iter::once(name.as_str()).chain(shortcodes.iter().map(String::as_str));
+
+    if labels().any(|label| label == keyword) {
+        Some(0)
+    } else if labels().any(|label| label.split_whitespace().any(|word| word.starts_with(keyword))) {
+        Some(1)
+    } else if labels().any(|label| label.contains(keyword)) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// Split off a `:keyword` a text starts with, as in `:fire secret`
+/// Returns the keyword and the trimmed rest
+pub fn split_leading_keyword(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start().strip_prefix(':')?;
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let keyword = text[..end].trim_end_matches(':');
+
+    let is_keyword = !keyword.is_empty()
+        && keyword.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '+'));
+
+    is_keyword.then(|| (keyword, text[end..].trim_start()))
+}
+
+/// Split off the emoji a text starts with, keeping flags, skin tones, ZWJ
+/// sequences and keycaps intact
+/// Returns the emoji and the trimmed rest, or None if the text does not start with an emoji
+pub fn split_leading_emoji(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    let first = text.graphemes(true).next()?;
+
+    is_emoji(first).then(|| (first, text[first.len()..].trim_start()))
+}
+
+/// The text itself if it is exactly one emoji, surrounding whitespace aside
+pub fn single_emoji(text: &str) -> Option<&str> {
+    let text = text.trim();
+    let mut graphemes = text.graphemes(true);
+
+    match (graphemes.next(), graphemes.next()) {
+        (Some(emoji), None) if is_emoji(emoji) => Some(emoji),
+        _ => None,
+    }
+}
+
+/// Whether a grapheme cluster is a single emoji
+pub fn is_emoji(grapheme: &str) -> bool {
+    if emojis::get(grapheme).is_some() {
+        return true;
+    }
+
+    // Emojis newer than the bundled data: anything with a pictographic code point,
+    // an emoji presentation selector or a keycap
+    grapheme.chars().any(|c| matches!(c as u32, 0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2B00..=0x2BFF))
+        || grapheme.contains('\u{FE0F}')
+        || grapheme.contains('\u{20E3}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emoji_catalog_starts_with_presets() {
+        let catalog: Vec<_> = emoji_catalog().collect();
+        assert_eq!(&catalog[..EMOJI_LIST.len()], EMOJI_LIST);
+        assert!(catalog.len() > 1000);
+
+        let unique: std::collections::HashSet<_> = catalog.iter().collect();
+        assert_eq!(unique.len(), catalog.len());
+    }
+
+    #[test]
+    fn test_emoji_categories() {
+        for category in EmojiCategory::ALL {
+            assert_eq!(EmojiCategory::from_index(category.index()), Some(category));
+            assert!(!category.emojis().is_empty());
+        }
+        assert!(EmojiCategory::Flags.emojis().contains(&"🇱🇰"));
+        assert!(EmojiCategory::Food.emojis().contains(&"🍕"));
+    }
+
+    #[test]
+    fn test_keyword_search() {
+        assert_eq!(search_emojis("fire").first(), Some(&"🔥"));
+        assert_eq!(search_emojis(":pizza:").first(), Some(&"🍕"));
+        assert!(search_emojis("heart").len() > 10);
+        assert!(search_emojis("xyzzyplugh").is_empty());
+
+        assert_eq!(find_emoji("fire"), Some("🔥"));
+        assert_eq!(find_emoji("hear"), None);
+    }
+
+    #[test]
+    fn test_split_leading_keyword() {
+        assert_eq!(split_leading_keyword(":fire secret text"), Some(("fire", "secret text")));
+        assert_eq!(split_leading_keyword(":red_heart: hi"), Some(("red_heart", "hi")));
+        assert_eq!(split_leading_keyword(":pizza"), Some(("pizza", "")));
+        assert_eq!(split_leading_keyword(":) hello"), None);
+        assert_eq!(split_leading_keyword("fire secret"), None);
+    }
+
+    #[test]
+    fn test_split_leading_emoji_keeps_clusters_intact() {
+        for emoji in ["🇱🇰", "👍🏽", "👨‍👩‍👧‍👦", "1️⃣", "❤️", "🏳️‍🌈", "🫠"] {
+            let query = format!("{} secret text", emoji);
+            assert_eq!(split_leading_emoji(&query), Some((emoji, "secret text")));
+        }
+    }
+
+    #[test]
+    fn test_single_emoji() {
+        assert_eq!(single_emoji(" 👨‍👩‍👧‍👦 "), Some("👨‍👩‍👧‍👦"));
+        assert_eq!(single_emoji("🔥🔥"), None);
+        assert_eq!(single_emoji("🔥 is hot"), None);
+        assert_eq!(single_emoji("a"), None);
+    }
+
+    #[test]
+    fn test_split_leading_emoji_rejects_text() {
+        assert_eq!(split_leading_emoji("hello 🔥"), None);
+        assert_eq!(split_leading_emoji("1 apple"), None);
+        assert_eq!(split_leading_emoji(""), None);
+    }
+}
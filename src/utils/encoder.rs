@@ -1,4 +1,9 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use crate::utils::compression::{compress, decompress, CompressionError};
+use crate::utils::crypto::{decrypt, encrypt, new_salt, SALT_LENGTH, TAG_LENGTH};
+use crate::utils::file_id_decoder::{file_id_from_bytes, file_id_to_bytes, FileIdError, SendMethod};
 
 #[derive(Error, Debug)]
 pub enum EncoderError {
@@ -6,18 +11,68 @@ pub enum EncoderError {
     InvalidByte(u8),
     #[error("UTF-8 encoding error")]
     Utf8Error(#[from] std::string::FromUtf8Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid file ID: {0}")]
+    InvalidFileId(#[from] FileIdError),
+    #[error("Encoded payload is truncated")]
+    TruncatedPayload,
+    #[error("Invalid compressed data: {0}")]
+    InvalidCompression(#[from] CompressionError),
+    #[error("This message expired on {0}")]
+    Expired(DateTime<Utc>),
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("Key derivation task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
 }
 
 // Variation selectors block https://unicode.org/charts/nameslist/n_FE00.html
 // VS1..=VS16
 const VARIATION_SELECTOR_START: u32 = 0xFE00;
 const VARIATION_SELECTOR_END: u32 = 0xFE0F;
+const TEXT_PRESENTATION_SELECTOR: char = '\u{FE0E}';
+const EMOJI_PRESENTATION_SELECTOR: char = '\u{FE0F}';
 
 // Variation selectors supplement https://unicode.org/charts/nameslist/n_E0100.html
 // VS17..=VS256
 const VARIATION_SELECTOR_SUPPLEMENT_START: u32 = 0xE0100;
 const VARIATION_SELECTOR_SUPPLEMENT_END: u32 = 0xE01EF;
 
+// Tag characters https://unicode.org/charts/nameslist/n_E0000.html
+// TAG SPACE..=TAG SOLIDUS, one per nibble. Flags like 🏴󠁧󠁢󠁳󠁣󠁴󠁿 only use tag letters and digits
+const TAG_NIBBLE_START: u32 = 0xE0020;
+const TAG_NIBBLE_END: u32 = 0xE002F;
+
+// Prefixes that mark a decoded payload as Telegram media
+const FILE_PREFIX: &str = "TG_FILE_";
+// Albums used to be stored as text, still decoded for older emojis
+const ALBUM_PREFIX: &str = "TG_ALBUM_";
+// file_ids are base64url, so a comma never appears inside one
+const ALBUM_SEPARATOR: &str = ",";
+// Non-file message content, stored as JSON after the prefix
+const CONTENT_PREFIX: &str = "TG_CONTENT_";
+// Tag of a compact file payload, followed by the binary file_id
+// 0xF5 never occurs in UTF-8, so it cannot be confused with a text payload
+const COMPACT_FILE_TAG: u8 = 0xF5;
+// Tag of a compact file payload with metadata: a flags byte, the flagged
+// fields in order, then the binary file_id
+const FILE_WITH_METADATA_TAG: u8 = 0xF6;
+const FLAG_SEND_METHOD: u8 = 1;
+// Older payloads carried the chat and message the file came from, it is now
+// kept server-side in EncodedMediaRecord and skipped when decoding
+const FLAG_ORIGIN: u8 = 1 << 1;
+// Likewise the storage channel message id, now looked up in EncodedMediaRecord
+const FLAG_STORAGE_MESSAGE: u8 = 1 << 2;
+// Tag of a text payload with options: a flags byte, the flagged fields in
+// order, then the text, compressed before it is encrypted
+const SEALED_TEXT_TAG: u8 = 0xF7;
+const FLAG_EXPIRES: u8 = 1;
+const FLAG_PASSWORD: u8 = 1 << 1;
+const FLAG_COMPRESSED: u8 = 1 << 2;
+// Tag of a compact album payload: each binary file_id preceded by its length byte
+const ALBUM_TAG: u8 = 0xF8;
+
 /// Convert a byte (0-255) to a variation selector character
 fn to_variation_selector(byte: u8) -> Result<char, EncoderError> {
     if byte < 16 {
@@ -42,11 +97,52 @@ fn from_variation_selector(code_point: u32) -> Option<u8> {
     }
 }
 
+/// Convert a tag character back to a nibble
+fn from_tag_nibble(code_point: u32) -> Option<u8> {
+    (TAG_NIBBLE_START..=TAG_NIBBLE_END)
+        .contains(&code_point)
+        .then(|| (code_point - TAG_NIBBLE_START) as u8)
+}
+
+/// Whether text contains variation selectors or tag characters, i.e. may carry hidden data
+pub fn has_variation_selectors(text: &str) -> bool {
+    text.chars()
+        .any(|c| from_variation_selector(c as u32).is_some() || from_tag_nibble(c as u32).is_some())
+}
+
 /// Encode text into an emoji by appending invisible variation selectors
 pub fn encode(emoji: &str, text: &str) -> Result<String, EncoderError> {
-    let bytes = text.as_bytes();
+    encode_bytes(emoji, text.as_bytes())
+}
+
+/// Encode raw bytes into an emoji
+fn encode_bytes(emoji: &str, bytes: &[u8]) -> Result<String, EncoderError> {
+    encode_bytes_as(emoji, bytes, CarrierScheme::Selectors)
+}
+
+/// Encode raw bytes into an emoji with the given invisible characters
+fn encode_bytes_as(emoji: &str, bytes: &[u8], scheme: CarrierScheme) -> Result<String, EncoderError> {
     let mut encoded = String::from(emoji);
 
+    if scheme == CarrierScheme::Tags {
+        for &byte in bytes {
+            for nibble in [byte >> 4, byte & 0xF] {
+                let tag = TAG_NIBBLE_START + nibble as u32;
+                encoded.push(char::from_u32(tag).ok_or(EncoderError::InvalidByte(byte))?);
+            }
+        }
+        return Ok(encoded);
+    }
+
+    // The decoder skips a presentation selector right after the emoji. If the data
+    // itself starts with one, add a real one first so that byte is not lost
+    if let Some(&first) = bytes.first()
+        && is_presentation_selector(to_variation_selector(first)?)
+        && emoji.chars().last().is_some_and(|c| from_variation_selector(c as u32).is_none())
+    {
+        encoded.push(EMOJI_PRESENTATION_SELECTOR);
+    }
+
     for &byte in bytes {
         let selector = to_variation_selector(byte)?;
         encoded.push(selector);
@@ -57,51 +153,482 @@ pub fn encode(emoji: &str, text: &str) -> Result<String, EncoderError> {
 
 /// Decode hidden text from an emoji with variation selectors
 pub fn decode(text: &str) -> Result<String, EncoderError> {
+    String::from_utf8(decode_bytes(text)).map_err(EncoderError::from)
+}
+
+/// Decode the raw bytes hidden in an emoji
+fn decode_bytes(text: &str) -> Vec<u8> {
     let mut decoded_bytes = Vec::new();
+    let mut previous: Option<char> = None;
+    let mut high_nibble: Option<u8> = None;
 
     for ch in text.chars() {
-        if let Some(byte) = from_variation_selector(ch as u32) {
-            decoded_bytes.push(byte);
-        } else if !decoded_bytes.is_empty() {
+        // Tag characters carry a byte in two halves
+        if let Some(nibble) = from_tag_nibble(ch as u32) {
+            match high_nibble.take() {
+                Some(high) => decoded_bytes.push(high << 4 | nibble),
+                None => high_nibble = Some(nibble),
+            }
+            previous = Some(ch);
+            continue;
+        }
+
+        match from_variation_selector(ch as u32) {
+            // The emoji's own presentation selector, as in ❤️, not hidden data
+            Some(_) if decoded_bytes.is_empty()
+                && is_presentation_selector(ch)
+                && previous.is_some_and(|p| from_variation_selector(p as u32).is_none()) => {}
+            Some(byte) => decoded_bytes.push(byte),
             // Stop when we encounter a non-variation selector after starting to decode
-            break;
+            None if !decoded_bytes.is_empty() => break,
+            // Skip the emoji itself at the beginning
+            None => {}
+        }
+        previous = Some(ch);
+    }
+
+    decoded_bytes
+}
+
+/// VS15 and VS16 select text or emoji presentation and are part of many emojis
+fn is_presentation_selector(ch: char) -> bool {
+    ch == TEXT_PRESENTATION_SELECTOR || ch == EMOJI_PRESENTATION_SELECTOR
+}
+
+/// Invisible characters the hidden bytes are written with
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CarrierScheme {
+    /// One variation selector per byte
+    #[default]
+    Selectors,
+    /// Two tag characters per byte, for apps that drop variation selectors
+    Tags,
+}
+
+impl CarrierScheme {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CarrierScheme::Selectors => "variation selectors",
+            CarrierScheme::Tags => "tag characters",
+        }
+    }
+}
+
+/// Extra protection for a hidden text message
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncodeOptions {
+    /// Needed to read the message
+    pub password: Option<String>,
+    /// The message can't be decoded once this much time has passed
+    pub ttl: Option<TimeDelta>,
+    pub compress: bool,
+    pub scheme: CarrierScheme,
+}
+
+impl EncodeOptions {
+    /// Whether the text needs a sealed payload instead of its plain bytes
+    fn is_sealed(&self) -> bool {
+        self.password.is_some() || self.ttl.is_some() || self.compress
+    }
+
+    /// Short description of the options in effect, empty for the defaults
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if self.password.is_some() {
+            parts.push("🔒 password".to_string());
+        }
+        if let Some(ttl) = self.ttl {
+            parts.push(format!("⏳ expires in {}", format_ttl(ttl)));
+        }
+        if self.compress {
+            parts.push("🗜 compressed".to_string());
+        }
+        if self.scheme != CarrierScheme::default() {
+            parts.push(self.scheme.name().to_string());
+        }
+        parts.join(" · ")
+    }
+}
+
+/// Largest unit a time span is a whole number of, e.g. "90m" or "2d"
+fn format_ttl(ttl: TimeDelta) -> String {
+    let seconds = ttl.num_seconds();
+    let (unit, size) = [("w", 604_800), ("d", 86_400), ("h", 3600), ("m", 60), ("s", 1)]
+        .into_iter()
+        .find(|(_, size)| seconds % size == 0)
+        .unwrap_or(("s", 1));
+    format!("{}{}", seconds / size, unit)
+}
+
+/// The hidden bytes of a text message, built once and written after any number of emojis
+#[derive(Debug, Clone)]
+pub struct TextPayload {
+    bytes: Vec<u8>,
+    scheme: CarrierScheme,
+}
+
+impl TextPayload {
+    pub fn new(text: &str, options: &EncodeOptions) -> Self {
+        let bytes = if options.is_sealed() {
+            seal_text(text, options, Utc::now())
+        } else {
+            text.as_bytes().to_vec()
+        };
+
+        Self { bytes, scheme: options.scheme }
+    }
+
+    /// Like new, but derives a password key on the blocking pool, it takes a while
+    pub async fn new_blocking(text: &str, options: &EncodeOptions) -> Result<Self, EncoderError> {
+        if options.password.is_none() {
+            return Ok(Self::new(text, options));
         }
-        // Skip the emoji itself at the beginning
+
+        let (text, options) = (text.to_string(), options.clone());
+        Ok(tokio::task::spawn_blocking(move || Self::new(&text, &options)).await?)
+    }
+
+    pub fn encode(&self, emoji: &str) -> Result<String, EncoderError> {
+        encode_bytes_as(emoji, &self.bytes, self.scheme)
+    }
+}
+
+/// Build a sealed text payload, see SEALED_TEXT_TAG
+fn seal_text(text: &str, options: &EncodeOptions, now: DateTime<Utc>) -> Vec<u8> {
+    let mut flags = 0;
+    let mut header = Vec::new();
+    let mut body = text.as_bytes().to_vec();
+
+    if let Some(ttl) = options.ttl {
+        flags |= FLAG_EXPIRES;
+        header.extend((now + ttl).timestamp().to_le_bytes());
+    }
+
+    // Only keep the compressed form when it is actually smaller
+    if options.compress {
+        let compressed = compress(&body);
+        if compressed.len() < body.len() {
+            flags |= FLAG_COMPRESSED;
+            body = compressed;
+        }
+    }
+
+    if options.password.is_some() {
+        flags |= FLAG_PASSWORD;
+    }
+    let mut data = vec![SEALED_TEXT_TAG, flags];
+    data.extend(header);
+
+    if let Some(password) = &options.password {
+        // The flags and expiry are authenticated, so the expiry can't be pushed back
+        let salt = new_salt();
+        body = encrypt(password, &salt, &data, &body);
+        data.extend(salt);
+    }
+
+    data.extend(body);
+    data
+}
+
+/// Inverse of seal_text, up to the password
+fn open_sealed_text(data: &[u8], now: DateTime<Utc>) -> Result<DecodedPayload, EncoderError> {
+    let (&flags, mut rest) = data.split_first().ok_or(EncoderError::TruncatedPayload)?;
+
+    if flags & FLAG_EXPIRES != 0 {
+        let (expires_at, remaining) = rest.split_first_chunk::<8>().ok_or(EncoderError::TruncatedPayload)?;
+        let expires_at = DateTime::from_timestamp(i64::from_le_bytes(*expires_at), 0)
+            .ok_or(EncoderError::TruncatedPayload)?;
+        if now > expires_at {
+            return Err(EncoderError::Expired(expires_at));
+        }
+        rest = remaining;
+    }
+
+    let compressed = flags & FLAG_COMPRESSED != 0;
+    if flags & FLAG_PASSWORD != 0 {
+        let header = [&[SEALED_TEXT_TAG], &data[..data.len() - rest.len()]].concat();
+        let (salt, data) = rest.split_at_checked(SALT_LENGTH).ok_or(EncoderError::TruncatedPayload)?;
+        // Catch a cut off message before asking for the password
+        if data.len() < TAG_LENGTH {
+            return Err(EncoderError::TruncatedPayload);
+        }
+        return Ok(DecodedPayload::Locked(LockedText {
+            header,
+            salt: salt.to_vec(),
+            data: data.to_vec(),
+            compressed,
+        }));
+    }
+
+    Ok(DecodedPayload::Text(open_text_body(rest, compressed)?))
+}
+
+fn open_text_body(body: &[u8], compressed: bool) -> Result<String, EncoderError> {
+    let body = if compressed { decompress(body)? } else { body.to_vec() };
+    Ok(String::from_utf8(body)?)
+}
+
+/// A password protected text message
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockedText {
+    /// Tag, flags and expiry, authenticated along with the text
+    header: Vec<u8>,
+    salt: Vec<u8>,
+    data: Vec<u8>,
+    compressed: bool,
+}
+
+impl LockedText {
+    pub fn unlock(&self, password: &str) -> Result<String, EncoderError> {
+        let body = decrypt(password, &self.salt, &self.header, &self.data).ok_or(EncoderError::WrongPassword)?;
+        open_text_body(&body, self.compressed)
     }
 
-    String::from_utf8(decoded_bytes).map_err(EncoderError::from)
+    /// Like unlock, on the blocking pool so key derivation doesn't stall other updates
+    pub async fn unlock_blocking(self, password: &str) -> Result<String, EncoderError> {
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || self.unlock(&password)).await?
+    }
+}
+
+/// Message a file was originally sent in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MessageOrigin {
+    pub chat_id: i64,
+    pub message_id: i32,
+}
+
+/// A file hidden in an emoji, with what is needed to send it again
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedFile {
+    pub file_id: String,
+    /// How the file was originally sent, which may differ from the type in its file_id
+    pub send_method: Option<SendMethod>,
+}
+
+impl EncodedFile {
+    pub fn new(file_id: impl Into<String>) -> Self {
+        Self {
+            file_id: file_id.into(),
+            send_method: None,
+        }
+    }
 }
 
-/// Encode a Telegram file_id into an emoji
-pub fn encode_file_id(emoji: &str, file_id: &str) -> Result<String, EncoderError> {
-    let file_data = format!("TG_FILE_{}", file_id.trim());
-    encode(emoji, &file_data)
+/// Encode a Telegram file into an emoji
+/// File IDs that can be parsed are stored in their binary form, which needs
+/// one selector per byte instead of one per base64 character plus the TG_FILE_ prefix
+pub fn encode_file(emoji: &str, file: &EncodedFile) -> Result<String, EncoderError> {
+    let file_id = file.file_id.trim();
+
+    // Unparseable file_ids keep the text form, without metadata
+    let Ok(file_id_bytes) = file_id_to_bytes(file_id) else {
+        return encode(emoji, &format!("{}{}", FILE_PREFIX, file_id));
+    };
+
+    let mut flags = 0;
+    let mut metadata = Vec::new();
+    if let Some(method) = file.send_method {
+        flags |= FLAG_SEND_METHOD;
+        metadata.push(method.tag());
+    }
+
+    let mut data = if flags == 0 {
+        vec![COMPACT_FILE_TAG]
+    } else {
+        let mut data = vec![FILE_WITH_METADATA_TAG, flags];
+        data.extend(metadata);
+        data
+    };
+    data.extend(file_id_bytes);
+
+    encode_bytes(emoji, &data)
 }
 
-/// Decode and check if the decoded text is a file_id
-/// Returns (is_file, content) where:
-/// - is_file: true if it's a file_id, false if it's regular text
-/// - content: the file_id (without TG_FILE_ prefix) or the decoded text
-pub fn decode_with_file_check(text: &str) -> Result<(bool, String), EncoderError> {
+/// Inverse of the metadata part of encode_file
+fn decode_file_with_metadata(data: &[u8]) -> Result<EncodedFile, EncoderError> {
+    let (&flags, mut rest) = data.split_first().ok_or(EncoderError::TruncatedPayload)?;
+    let mut take = |len: usize| -> Result<&[u8], EncoderError> {
+        let (field, remaining) = rest.split_at_checked(len).ok_or(EncoderError::TruncatedPayload)?;
+        rest = remaining;
+        Ok(field)
+    };
+
+    let send_method = if flags & FLAG_SEND_METHOD != 0 {
+        SendMethod::from_tag(take(1)?[0])
+    } else {
+        None
+    };
+
+    // Message ids from older payloads, anyone can write them so they are never used
+    if flags & FLAG_ORIGIN != 0 {
+        take(12)?;
+    }
+    if flags & FLAG_STORAGE_MESSAGE != 0 {
+        take(4)?;
+    }
+
+    Ok(EncodedFile {
+        file_id: file_id_from_bytes(rest)?,
+        send_method,
+    })
+}
+
+/// Encode the file_ids of an album (media group) into a single emoji
+/// Like encode_file, the file_ids are stored in their binary form, and the
+/// album keeps the text form if any of them cannot be parsed
+pub fn encode_album(emoji: &str, file_ids: &[String]) -> Result<String, EncoderError> {
+    let ids: Vec<&str> = file_ids.iter().map(|id| id.trim()).collect();
+
+    let mut data = vec![ALBUM_TAG];
+    for id in &ids {
+        let bytes = match file_id_to_bytes(id) {
+            Ok(bytes) if bytes.len() <= u8::MAX as usize => bytes,
+            _ => return encode(emoji, &format!("{}{}", ALBUM_PREFIX, ids.join(ALBUM_SEPARATOR))),
+        };
+        data.push(bytes.len() as u8);
+        data.extend(bytes);
+    }
+
+    encode_bytes(emoji, &data)
+}
+
+/// Inverse of the binary form of encode_album
+fn decode_album(mut data: &[u8]) -> Result<Vec<String>, EncoderError> {
+    let mut file_ids = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let (bytes, rest) = rest.split_at_checked(len.into()).ok_or(EncoderError::TruncatedPayload)?;
+        file_ids.push(file_id_from_bytes(bytes)?);
+        data = rest;
+    }
+
+    if file_ids.is_empty() {
+        return Err(EncoderError::TruncatedPayload);
+    }
+    Ok(file_ids)
+}
+
+/// Encode a non-file message (location, contact, poll, ...) into an emoji
+pub fn encode_content(emoji: &str, content: &SharedContent) -> Result<String, EncoderError> {
+    let json = serde_json::to_string(content)?;
+    encode(emoji, &format!("{}{}", CONTENT_PREFIX, json))
+}
+
+/// Message kinds that carry no file but can be recreated from their fields
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SharedContent {
+    Location {
+        latitude: f64,
+        longitude: f64,
+    },
+    Venue {
+        latitude: f64,
+        longitude: f64,
+        title: String,
+        address: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        foursquare_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        google_place_id: Option<String>,
+    },
+    Contact {
+        phone_number: String,
+        first_name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        vcard: Option<String>,
+    },
+    Poll {
+        question: String,
+        options: Vec<String>,
+        is_anonymous: bool,
+        allows_multiple_answers: bool,
+    },
+    Dice {
+        emoji: String,
+    },
+}
+
+impl SharedContent {
+    /// Human-readable name used in bot replies
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            SharedContent::Location { .. } => "location",
+            SharedContent::Venue { .. } => "venue",
+            SharedContent::Contact { .. } => "contact",
+            SharedContent::Poll { .. } => "poll",
+            SharedContent::Dice { .. } => "dice",
+        }
+    }
+}
+
+/// Content recovered from an encoded emoji
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodedPayload {
+    Text(String),
+    File(EncodedFile),
+    Album(Vec<String>),
+    Content(SharedContent),
+    /// Text that needs a password
+    Locked(LockedText),
+}
+
+/// Decode an emoji and detect what kind of content is hidden in it
+pub fn decode_payload(text: &str) -> Result<DecodedPayload, EncoderError> {
+    let bytes = decode_bytes(text);
+
+    match bytes.split_first() {
+        Some((&COMPACT_FILE_TAG, file_id)) => {
+            return Ok(DecodedPayload::File(EncodedFile::new(file_id_from_bytes(file_id)?)));
+        }
+        Some((&FILE_WITH_METADATA_TAG, data)) => {
+            return Ok(DecodedPayload::File(decode_file_with_metadata(data)?));
+        }
+        Some((&SEALED_TEXT_TAG, data)) => return open_sealed_text(data, Utc::now()),
+        Some((&ALBUM_TAG, data)) => return Ok(DecodedPayload::Album(decode_album(data)?)),
+        _ => {}
+    }
+
     let decoded = decode(text)?;
 
-    // Check if TG_FILE_ exists anywhere in the decoded text
-    if let Some(pos) = decoded.find("TG_FILE_") {
-        // Extract everything after TG_FILE_
-        let file_part = &decoded[pos + 8..]; // 8 is the length of "TG_FILE_"
+    // Structured content is JSON, so it may legitimately contain whitespace
+    if let Some(json) = decoded.strip_prefix(CONTENT_PREFIX)
+        && let Ok(content) = serde_json::from_str::<SharedContent>(json)
+    {
+        return Ok(DecodedPayload::Content(content));
+    }
 
-        // Remove all whitespace from the file_id
-        let file_id = file_part.chars()
-            .filter(|c| !c.is_whitespace())
-            .collect::<String>();
+    // Check for a text album before a single file
+    if let Some(pos) = decoded.find(ALBUM_PREFIX) {
+        let file_ids: Vec<String> = decoded[pos + ALBUM_PREFIX.len()..]
+            .split(ALBUM_SEPARATOR)
+            .map(strip_whitespace)
+            .filter(|id| !id.is_empty())
+            .collect();
+
+        if !file_ids.is_empty() {
+            return Ok(DecodedPayload::Album(file_ids));
+        }
+    }
+
+    // Check if TG_FILE_ exists anywhere in the decoded text
+    if let Some(pos) = decoded.find(FILE_PREFIX) {
+        let file_id = strip_whitespace(&decoded[pos + FILE_PREFIX.len()..]);
 
         if !file_id.is_empty() {
-            return Ok((true, file_id));
+            return Ok(DecodedPayload::File(EncodedFile::new(file_id)));
         }
     }
 
     // Not a file, return the original decoded text
-    Ok((false, decoded))
+    Ok(DecodedPayload::Text(decoded))
+}
+
+/// Remove all whitespace from a file_id
+fn strip_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
 }
 
 #[cfg(test)]
@@ -141,4 +668,212 @@ mod tests {
         let decoded = decode(&encoded).unwrap();
         assert_eq!(decoded, text);
     }
+
+    #[test]
+    fn test_emoji_with_presentation_selectors() {
+        for emoji in ["❤️", "1️⃣", "🏳️‍🌈", "☺︎"] {
+            let encoded = encode(emoji, "secret").unwrap();
+            assert!(encoded.starts_with(emoji));
+            assert_eq!(decode(&encoded).unwrap(), "secret");
+
+            // A payload starting with the bytes of VS15/VS16 keeps them
+            let encoded = encode(emoji, "\u{0f}\u{0e}x").unwrap();
+            assert_eq!(decode(&encoded).unwrap(), "\u{0f}\u{0e}x");
+        }
+
+        let encoded = encode("😀", "\u{0f}x").unwrap();
+        assert_eq!(decode(&encoded).unwrap(), "\u{0f}x");
+
+        // A plain emoji carries nothing
+        assert_eq!(decode("❤️ hello").unwrap(), "");
+    }
+
+    #[test]
+    fn test_tag_scheme() {
+        let options = EncodeOptions { scheme: CarrierScheme::Tags, ..Default::default() };
+        let encoded = TextPayload::new("secret", &options).encode("🏴󠁧󠁢󠁳󠁣󠁴󠁿").unwrap();
+
+        assert!(has_variation_selectors(&encoded));
+        assert_eq!(decode(&encoded).unwrap(), "secret");
+        // A subdivision flag alone hides nothing
+        assert!(!has_variation_selectors("🏴󠁧󠁢󠁳󠁣󠁴󠁿"));
+    }
+
+    #[test]
+    fn test_sealed_text() {
+        let text = "meet me at the usual place, the usual place";
+        let options = EncodeOptions {
+            password: Some("hunter2".to_string()),
+            ttl: Some(TimeDelta::days(1)),
+            compress: true,
+            ..Default::default()
+        };
+        let encoded = TextPayload::new(text, &options).encode("🔥").unwrap();
+
+        let Ok(DecodedPayload::Locked(locked)) = decode_payload(&encoded) else {
+            panic!("expected a locked payload");
+        };
+        assert_eq!(locked.unlock("hunter2").unwrap(), text);
+        assert!(matches!(locked.unlock("hunter3"), Err(EncoderError::WrongPassword)));
+
+        let compressed_only = EncodeOptions { compress: true, ..Default::default() };
+        let encoded = TextPayload::new(text, &compressed_only).encode("🔥").unwrap();
+        assert_eq!(decode_payload(&encoded).unwrap(), DecodedPayload::Text(text.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_sealed_text_on_blocking_pool() {
+        let options = EncodeOptions { password: Some("hunter2".to_string()), ..Default::default() };
+        let encoded = TextPayload::new_blocking("secret", &options).await.unwrap().encode("🔥").unwrap();
+
+        let Ok(DecodedPayload::Locked(locked)) = decode_payload(&encoded) else {
+            panic!("expected a locked payload");
+        };
+        assert_eq!(locked.unlock_blocking("hunter2").await.unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_expired_text() {
+        let options = EncodeOptions { ttl: Some(TimeDelta::hours(1)), ..Default::default() };
+        let sealed = seal_text("secret", &options, Utc::now() - TimeDelta::hours(2));
+
+        assert!(matches!(open_sealed_text(&sealed[1..], Utc::now()), Err(EncoderError::Expired(_))));
+        assert_eq!(
+            open_sealed_text(&sealed[1..], Utc::now() - TimeDelta::minutes(90)).unwrap(),
+            DecodedPayload::Text("secret".to_string())
+        );
+        assert_eq!(options.summary(), "⏳ expires in 1h");
+    }
+
+    #[test]
+    fn test_file_payload() {
+        let file = EncodedFile::new("AgACAgIAAxkBAAIB");
+        let encoded = encode_file("🔥", &file).unwrap();
+        assert_eq!(decode_payload(&encoded).unwrap(), DecodedPayload::File(file));
+    }
+
+    fn sample_file_id() -> String {
+        use crate::utils::file_id_decoder::{DecodedFileId, FileType};
+
+        DecodedFileId {
+            type_id: 5,
+            file_type: FileType::Document,
+            dc_id: 2,
+            file_reference: Some(vec![1, 0, 0, 0, 42, 7, 7, 7]),
+            url: None,
+            media_id: Some(5_234_567_890_123),
+            access_hash: -8_123_456_789,
+            photo_size_source: None,
+            legacy_photo_location: None,
+            version: 4,
+            sub_version: 47,
+        }
+        .to_file_id()
+    }
+
+    #[test]
+    fn test_compact_file_payload() {
+        let file_id = sample_file_id();
+
+        let compact = encode_file("🔥", &EncodedFile::new(&file_id)).unwrap();
+        let legacy = encode("🔥", &format!("{}{}", FILE_PREFIX, file_id)).unwrap();
+        assert!(compact.chars().count() < legacy.chars().count() * 3 / 4);
+
+        // Both forms decode to the same file_id
+        assert_eq!(decode_payload(&compact).unwrap(), DecodedPayload::File(EncodedFile::new(&file_id)));
+        assert_eq!(decode_payload(&legacy).unwrap(), DecodedPayload::File(EncodedFile::new(file_id)));
+    }
+
+    #[test]
+    fn test_file_payload_with_metadata() {
+        let file = EncodedFile {
+            file_id: sample_file_id(),
+            send_method: Some(SendMethod::Animation),
+        };
+
+        let encoded = encode_file("🎞", &file).unwrap();
+        assert_eq!(decode_payload(&encoded).unwrap(), DecodedPayload::File(file));
+
+        // Cutting off the file_id must not produce a wrong file
+        let truncated: String = encoded.chars().take(10).collect();
+        assert!(decode_payload(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_file_payload_message_ids_are_ignored() {
+        // Payloads from before the origin and storage copy moved server-side
+        let flags = FLAG_SEND_METHOD | FLAG_ORIGIN | FLAG_STORAGE_MESSAGE;
+        let mut data = vec![FILE_WITH_METADATA_TAG, flags, SendMethod::Photo.tag()];
+        data.extend(123_456_789i64.to_le_bytes());
+        data.extend(42i32.to_le_bytes());
+        data.extend(1_000_007i32.to_le_bytes());
+        data.extend(file_id_to_bytes(&sample_file_id()).unwrap());
+
+        let file = EncodedFile {
+            send_method: Some(SendMethod::Photo),
+            ..EncodedFile::new(sample_file_id())
+        };
+        assert_eq!(decode_payload(&encode_bytes("🔥", &data).unwrap()).unwrap(), DecodedPayload::File(file));
+    }
+
+    #[test]
+    fn test_album_payload() {
+        let file_ids = vec!["AgACAgIAAxkBAAIB".to_string(), "BAACAgIAAxkBAAIC".to_string()];
+
+        let encoded = encode_album("🎉", &file_ids).unwrap();
+        assert!(encoded.starts_with("🎉"));
+        assert_eq!(decode_payload(&encoded).unwrap(), DecodedPayload::Album(file_ids));
+    }
+
+    #[test]
+    fn test_compact_album_payload() {
+        let file_ids = vec![sample_file_id(), sample_file_id()];
+
+        let compact = encode_album("🎉", &file_ids).unwrap();
+        let legacy = encode("🎉", &format!("{}{}", ALBUM_PREFIX, file_ids.join(ALBUM_SEPARATOR))).unwrap();
+        assert!(compact.chars().count() < legacy.chars().count() * 3 / 4);
+
+        // Both forms decode to the same album
+        assert_eq!(decode_payload(&compact).unwrap(), DecodedPayload::Album(file_ids.clone()));
+        assert_eq!(decode_payload(&legacy).unwrap(), DecodedPayload::Album(file_ids));
+
+        // Cutting off the last file_id must not produce a shorter album
+        let truncated: String = compact.chars().take(compact.chars().count() - 5).collect();
+        assert!(decode_payload(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_text_payload() {
+        let encoded = encode("👀", "just some text").unwrap();
+        assert_eq!(
+            decode_payload(&encoded).unwrap(),
+            DecodedPayload::Text("just some text".to_string())
+        );
+    }
+
+    #[test]
+    fn test_content_payload() {
+        let content = SharedContent::Poll {
+            question: "Where do we meet?".to_string(),
+            options: vec!["Park".to_string(), "Café".to_string()],
+            is_anonymous: true,
+            allows_multiple_answers: false,
+        };
+
+        let encoded = encode_content("📍", &content).unwrap();
+        assert_eq!(decode_payload(&encoded).unwrap(), DecodedPayload::Content(content));
+    }
+
+    #[test]
+    fn test_content_payload_optional_fields() {
+        let content = SharedContent::Contact {
+            phone_number: "+94 71 234 5678".to_string(),
+            first_name: "Malith".to_string(),
+            last_name: None,
+            vcard: Some("BEGIN:VCARD\nVERSION:3.0\nEND:VCARD".to_string()),
+        };
+
+        let encoded = encode_content("📇", &content).unwrap();
+        assert_eq!(decode_payload(&encoded).unwrap(), DecodedPayload::Content(content));
+    }
 }
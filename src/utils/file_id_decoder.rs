@@ -1,25 +1,188 @@
-/// Telegram File ID decoder
-/// Based on https://github.com/luckydonald/telegram_file_id
-///
-/// This module decodes Telegram file_ids to extract the file type.
-/// File IDs are base64url encoded and RLE (run-length) encoded.
+//! Telegram File ID decoder
+//! Based on https://github.com/luckydonald/telegram_file_id
+//!
+//! This module decodes Telegram file_ids to extract the file type and the
+//! location they point to, and serializes them back into file_id strings.
+//! File IDs are base64url encoded, with the body RLE (run-length) encoded
+//! and the version bytes appended after it as they are.
 
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FileIdError {
+    #[error("Invalid base64: {0}")]
+    InvalidBase64(#[from] base64::DecodeError),
+    #[error("File ID too short")]
+    TooShort,
+    #[error("Unsupported file ID version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unexpected end of data while reading {0}")]
+    UnexpectedEnd(&'static str),
+    #[error("Unknown photo size source: {0}")]
+    UnknownPhotoSizeSource(i32),
+    #[error("Invalid UTF-8 in web file URL")]
+    InvalidUrl,
+    #[error("{0} unexpected trailing bytes")]
+    TrailingBytes(usize),
+}
 
 /// File type constants from Telegram
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Serialized in snake_case; the aliases read the names older bot states used
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FileType {
+    /// Thumbnail of a photo, document or sticker set
+    Thumbnail,
+    /// Profile photo of a user or chat
+    ProfilePhoto,
     Photo,
-    Video,
     Voice,
+    Video,
     Document,
+    /// File from a secret chat
+    Encrypted,
+    /// Temporary file uploaded by the bot
+    Temp,
+    /// Static (WEBP/PNG) sticker
     Sticker,
+    /// Animated (TGS) sticker
+    #[serde(alias = "animated sticker")]
+    AnimatedSticker,
+    /// Video (WEBM) sticker
+    #[serde(alias = "video sticker")]
+    VideoSticker,
     Audio,
     Animation,
+    EncryptedThumbnail,
+    Wallpaper,
+    #[serde(alias = "video note")]
     VideoNote,
+    /// Decrypted Telegram Passport file
+    SecureRaw,
+    /// Telegram Passport file
+    Secure,
+    Background,
+    /// Media sent as a plain document
+    DocumentAsFile,
+    Ringtone,
+    CallLog,
+    PhotoStory,
+    VideoStory,
+    SelfDestructingPhoto,
+    SelfDestructingVideo,
+    SelfDestructingVideoNote,
+    SelfDestructingVoice,
     Unknown,
 }
 
+/// Bot API method used to send a file of a given type
+/// The discriminants are stored in encoded payloads, never renumber them
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum SendMethod {
+    Photo = 0,
+    Video = 1,
+    Voice = 2,
+    Document = 3,
+    Sticker = 4,
+    Audio = 5,
+    Animation = 6,
+    VideoNote = 7,
+}
+
+impl SendMethod {
+    const ALL: [SendMethod; 8] = [
+        SendMethod::Photo,
+        SendMethod::Video,
+        SendMethod::Voice,
+        SendMethod::Document,
+        SendMethod::Sticker,
+        SendMethod::Audio,
+        SendMethod::Animation,
+        SendMethod::VideoNote,
+    ];
+
+    /// Stable one-byte tag used in encoded payloads
+    pub fn tag(self) -> u8 {
+        self as u8
+    }
+
+    /// Inverse of tag
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        SendMethod::ALL.into_iter().find(|method| method.tag() == tag)
+    }
+}
+
+impl FileType {
+    /// How a file of this type can be sent again, None for thumbnails and unknown types
+    pub fn send_method(self) -> Option<SendMethod> {
+        let method = match self {
+            FileType::ProfilePhoto
+            | FileType::Photo
+            | FileType::Wallpaper
+            | FileType::PhotoStory
+            | FileType::SelfDestructingPhoto => SendMethod::Photo,
+            FileType::Video | FileType::VideoStory | FileType::SelfDestructingVideo => SendMethod::Video,
+            FileType::Voice | FileType::SelfDestructingVoice => SendMethod::Voice,
+            FileType::VideoNote | FileType::SelfDestructingVideoNote => SendMethod::VideoNote,
+            // The file_id does not record the sticker format, all of them use sendSticker
+            FileType::Sticker | FileType::AnimatedSticker | FileType::VideoSticker => SendMethod::Sticker,
+            FileType::Audio => SendMethod::Audio,
+            FileType::Animation => SendMethod::Animation,
+            FileType::Document
+            | FileType::Encrypted
+            | FileType::Temp
+            | FileType::SecureRaw
+            | FileType::Secure
+            | FileType::Background
+            | FileType::DocumentAsFile
+            | FileType::Ringtone
+            | FileType::CallLog => SendMethod::Document,
+            // Thumbnails are only served alongside their file, no send method accepts them
+            FileType::Thumbnail | FileType::EncryptedThumbnail | FileType::Unknown => return None,
+        };
+
+        Some(method)
+    }
+
+    /// Human-readable name
+    pub fn name(self) -> &'static str {
+        match self {
+            FileType::Thumbnail => "thumbnail",
+            FileType::ProfilePhoto => "profile photo",
+            FileType::Photo => "photo",
+            FileType::Voice => "voice message",
+            FileType::Video => "video",
+            FileType::Document => "document",
+            FileType::Encrypted => "encrypted file",
+            FileType::Temp => "temporary file",
+            FileType::Sticker => "sticker",
+            FileType::AnimatedSticker => "animated sticker",
+            FileType::VideoSticker => "video sticker",
+            FileType::Audio => "audio",
+            FileType::Animation => "animation",
+            FileType::EncryptedThumbnail => "encrypted thumbnail",
+            FileType::Wallpaper => "wallpaper",
+            FileType::VideoNote => "video note",
+            FileType::SecureRaw => "decrypted passport file",
+            FileType::Secure => "passport file",
+            FileType::Background => "background",
+            FileType::DocumentAsFile => "document",
+            FileType::Ringtone => "ringtone",
+            FileType::CallLog => "call log",
+            FileType::PhotoStory => "photo story",
+            FileType::VideoStory => "video story",
+            FileType::SelfDestructingPhoto => "self-destructing photo",
+            FileType::SelfDestructingVideo => "self-destructing video",
+            FileType::SelfDestructingVideoNote => "self-destructing video note",
+            FileType::SelfDestructingVoice => "self-destructing voice message",
+            FileType::Unknown => "file",
+        }
+    }
+}
+
 const TYPE_ID_FILE_REFERENCE_FLAG: u32 = 1 << 25;
 const TYPE_ID_WEB_LOCATION_FLAG: u32 = 1 << 24;
 
@@ -30,13 +193,148 @@ const TYPE_PHOTO: u32 = 2;
 const TYPE_VOICE: u32 = 3;
 const TYPE_VIDEO: u32 = 4;
 const TYPE_DOCUMENT: u32 = 5;
+const TYPE_ENCRYPTED: u32 = 6;
+const TYPE_TEMP: u32 = 7;
 const TYPE_STICKER: u32 = 8;
 const TYPE_AUDIO: u32 = 9;
 const TYPE_ANIMATION: u32 = 10;
+const TYPE_ENCRYPTED_THUMBNAIL: u32 = 11;
+const TYPE_WALLPAPER: u32 = 12;
 const TYPE_VIDEO_NOTE: u32 = 13;
+const TYPE_SECURE_RAW: u32 = 14;
+const TYPE_SECURE: u32 = 15;
+const TYPE_BACKGROUND: u32 = 16;
+const TYPE_DOCUMENT_AS_FILE: u32 = 17;
+const TYPE_RINGTONE: u32 = 18;
+const TYPE_CALL_LOG: u32 = 19;
+const TYPE_PHOTO_STORY: u32 = 20;
+const TYPE_VIDEO_STORY: u32 = 21;
+const TYPE_SELF_DESTRUCTING_PHOTO: u32 = 22;
+const TYPE_SELF_DESTRUCTING_VIDEO: u32 = 23;
+const TYPE_SELF_DESTRUCTING_VIDEO_NOTE: u32 = 24;
+const TYPE_SELF_DESTRUCTING_VOICE: u32 = 25;
+
+// Persistent file_id versions used by the Bot API
+const PERSISTENT_ID_VERSION_OLD: u8 = 2;
+const PERSISTENT_ID_VERSION: u8 = 4;
+
+// Telegram never writes longer runs of zeros than this
+const RLE_MAX_RUN: u8 = 250;
+
+// Sub-versions that changed the layout of photo locations
+const SUB_VERSION_PHOTO_SIZE_SOURCE: u8 = 22;
+const SUB_VERSION_NO_VOLUME_ID: u8 = 32;
+
+/// Where the image of a photo-like file_id comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum PhotoSizeSource {
+    Legacy { secret: i64 },
+    Thumbnail { file_type: u32, thumbnail_type: u32 },
+    DialogPhotoSmall { dialog_id: i64, dialog_access_hash: i64 },
+    DialogPhotoBig { dialog_id: i64, dialog_access_hash: i64 },
+    StickerSetThumbnail { sticker_set_id: i64, sticker_set_access_hash: i64 },
+    FullLegacy { volume_id: i64, secret: i64, local_id: i32 },
+    DialogPhotoSmallLegacy { dialog_id: i64, dialog_access_hash: i64, volume_id: i64, local_id: i32 },
+    DialogPhotoBigLegacy { dialog_id: i64, dialog_access_hash: i64, volume_id: i64, local_id: i32 },
+    StickerSetThumbnailLegacy { sticker_set_id: i64, sticker_set_access_hash: i64, volume_id: i64, local_id: i32 },
+    StickerSetThumbnailVersion { sticker_set_id: i64, sticker_set_access_hash: i64, version: i32 },
+}
+
+impl PhotoSizeSource {
+    /// Short human-readable description
+    pub fn describe(&self) -> String {
+        match self {
+            PhotoSizeSource::Legacy { .. } => "legacy photo".to_string(),
+            PhotoSizeSource::Thumbnail { thumbnail_type, .. } => {
+                match char::from_u32(*thumbnail_type) {
+                    Some(size) if size.is_ascii_alphanumeric() => format!("thumbnail, size '{}'", size),
+                    _ => format!("thumbnail, size {}", thumbnail_type),
+                }
+            }
+            PhotoSizeSource::DialogPhotoSmall { dialog_id, .. }
+            | PhotoSizeSource::DialogPhotoSmallLegacy { dialog_id, .. } => {
+                format!("small profile photo of chat {}", dialog_id)
+            }
+            PhotoSizeSource::DialogPhotoBig { dialog_id, .. }
+            | PhotoSizeSource::DialogPhotoBigLegacy { dialog_id, .. } => {
+                format!("big profile photo of chat {}", dialog_id)
+            }
+            PhotoSizeSource::StickerSetThumbnail { sticker_set_id, .. }
+            | PhotoSizeSource::StickerSetThumbnailLegacy { sticker_set_id, .. }
+            | PhotoSizeSource::StickerSetThumbnailVersion { sticker_set_id, .. } => {
+                format!("thumbnail of sticker set {}", sticker_set_id)
+            }
+            PhotoSizeSource::FullLegacy { .. } => "full legacy photo".to_string(),
+        }
+    }
+}
+
+/// Legacy photo location fields, present before sub-version 32
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyPhotoLocation {
+    pub volume_id: i64,
+    pub local_id: i32,
+}
+
+/// Everything a Telegram file_id contains
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFileId {
+    /// Type id with the flag bits removed
+    pub type_id: u32,
+    pub file_type: FileType,
+    pub dc_id: i32,
+    pub file_reference: Option<Vec<u8>>,
+    /// Set for files hosted on the web instead of Telegram servers
+    pub url: Option<String>,
+    /// Missing for web files
+    pub media_id: Option<i64>,
+    pub access_hash: i64,
+    /// Set for photo-like types (photos, thumbnails, profile photos, ...)
+    pub photo_size_source: Option<PhotoSizeSource>,
+    pub legacy_photo_location: Option<LegacyPhotoLocation>,
+    pub version: u8,
+    pub sub_version: u8,
+}
+
+impl DecodedFileId {
+    /// Multi-line human-readable description of what the file_id points to
+    pub fn describe(&self) -> String {
+        let mut lines = vec![
+            format!("Type: {:?} (type id {})", self.file_type, self.type_id),
+            format!("DC: {}", self.dc_id),
+        ];
+
+        if let Some(url) = &self.url {
+            lines.push(format!("Web URL: {}", url));
+        }
+        if let Some(media_id) = self.media_id {
+            lines.push(format!("Media ID: {}", media_id));
+        }
+        lines.push(format!("Access hash: {}", self.access_hash));
+
+        match &self.file_reference {
+            Some(reference) => lines.push(format!(
+                "File reference: {} bytes ({})",
+                reference.len(),
+                reference.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+            )),
+            None => lines.push("File reference: none".to_string()),
+        }
+
+        if let Some(source) = &self.photo_size_source {
+            lines.push(format!("Photo source: {}", source.describe()));
+        }
+        if let Some(legacy) = &self.legacy_photo_location {
+            lines.push(format!("Volume ID: {}, local ID: {}", legacy.volume_id, legacy.local_id));
+        }
+
+        lines.push(format!("Version: {}.{}", self.version, self.sub_version));
+        lines.join("\n")
+    }
+}
 
 /// Decode a Telegram file_id and extract the file type
-pub fn decode_file_type(file_id: &str) -> Result<FileType, Box<dyn std::error::Error + Send + Sync>> {
+pub fn decode_file_type(file_id: &str) -> Result<FileType, FileIdError> {
     // Step 1: Base64 URL-safe decode
     let decoded = URL_SAFE_NO_PAD.decode(file_id)?;
 
@@ -45,7 +343,7 @@ pub fn decode_file_type(file_id: &str) -> Result<FileType, Box<dyn std::error::E
 
     // Step 3: Read first 4 bytes as little-endian u32 (type_id)
     if rle_decoded.len() < 4 {
-        return Err("File ID too short".into());
+        return Err(FileIdError::TooShort);
     }
 
     let type_id = u32::from_le_bytes([
@@ -55,21 +353,350 @@ pub fn decode_file_type(file_id: &str) -> Result<FileType, Box<dyn std::error::E
         rle_decoded[3],
     ]);
 
-    // Step 4: Normalize type_id (remove flags)
-    let normalized_type_id = normalize_type_id(type_id);
+    // Step 4: Normalize type_id (remove flags) and map to FileType
+    Ok(file_type_from_id(normalize_type_id(type_id)))
+}
+
+/// Parse the full structure of a Telegram file_id
+pub fn parse_file_id(file_id: &str) -> Result<DecodedFileId, FileIdError> {
+    let decoded = URL_SAFE_NO_PAD.decode(file_id.trim())?;
+    let (version, sub_version, body) = split_version(&decoded)?;
+    let body = rle_decode(body);
 
-    // Step 5: Map to FileType
-    Ok(match normalized_type_id {
-        TYPE_PHOTO | TYPE_THUMBNAIL | TYPE_PROFILE_PHOTO => FileType::Photo,
-        TYPE_VIDEO => FileType::Video,
+    let mut reader = Reader::new(&body);
+
+    let raw_type_id = reader.read_i32("type id")? as u32;
+    let has_file_reference = raw_type_id & TYPE_ID_FILE_REFERENCE_FLAG != 0;
+    let is_web = raw_type_id & TYPE_ID_WEB_LOCATION_FLAG != 0;
+    let type_id = normalize_type_id(raw_type_id);
+
+    let dc_id = reader.read_i32("dc id")?;
+
+    let file_reference = if has_file_reference {
+        Some(reader.read_bytes("file reference")?)
+    } else {
+        None
+    };
+
+    let mut parsed = DecodedFileId {
+        type_id,
+        file_type: file_type_from_id(type_id),
+        dc_id,
+        file_reference,
+        url: None,
+        media_id: None,
+        access_hash: 0,
+        photo_size_source: None,
+        legacy_photo_location: None,
+        version,
+        sub_version,
+    };
+
+    if is_web {
+        let url = reader.read_bytes("web url")?;
+        parsed.url = Some(String::from_utf8(url).map_err(|_| FileIdError::InvalidUrl)?);
+        parsed.access_hash = reader.read_i64("access hash")?;
+    } else {
+        parsed.media_id = Some(reader.read_i64("media id")?);
+        parsed.access_hash = reader.read_i64("access hash")?;
+
+        if is_photo_type(type_id) {
+            parse_photo_location(&mut reader, &mut parsed)?;
+        }
+    }
+
+    if reader.remaining() > 0 {
+        return Err(FileIdError::TrailingBytes(reader.remaining()));
+    }
+
+    Ok(parsed)
+}
+
+/// Split the version trailer off the base64-decoded bytes of a file_id
+/// TDLib zero-encodes only the body, the sub-version and version bytes follow it
+/// as they are. Version 4 stores a sub-version before the version byte
+fn split_version(data: &[u8]) -> Result<(u8, u8, &[u8]), FileIdError> {
+    match data.split_last() {
+        Some((&PERSISTENT_ID_VERSION, rest)) => match rest.split_last() {
+            Some((&sub_version, body)) => Ok((PERSISTENT_ID_VERSION, sub_version, body)),
+            None => Err(FileIdError::TooShort),
+        },
+        Some((&PERSISTENT_ID_VERSION_OLD, body)) => Ok((PERSISTENT_ID_VERSION_OLD, 0, body)),
+        Some((&version, _)) => Err(FileIdError::UnsupportedVersion(version)),
+        None => Err(FileIdError::TooShort),
+    }
+}
+
+/// Parse the photo-specific part of a file_id
+fn parse_photo_location(reader: &mut Reader, parsed: &mut DecodedFileId) -> Result<(), FileIdError> {
+    let has_volume_id = parsed.sub_version < SUB_VERSION_NO_VOLUME_ID;
+
+    let volume_id = if has_volume_id {
+        Some(reader.read_i64("volume id")?)
+    } else {
+        None
+    };
+
+    let source = if parsed.sub_version >= SUB_VERSION_PHOTO_SIZE_SOURCE {
+        read_photo_size_source(reader)?
+    } else {
+        PhotoSizeSource::Legacy { secret: reader.read_i64("secret")? }
+    };
+    parsed.photo_size_source = Some(source);
+
+    if let Some(volume_id) = volume_id {
+        let local_id = reader.read_i32("local id")?;
+        parsed.legacy_photo_location = Some(LegacyPhotoLocation { volume_id, local_id });
+    }
+
+    Ok(())
+}
+
+fn read_photo_size_source(reader: &mut Reader) -> Result<PhotoSizeSource, FileIdError> {
+    let source_type = reader.read_i32("photo size source")?;
+
+    Ok(match source_type {
+        0 => PhotoSizeSource::Legacy {
+            secret: reader.read_i64("secret")?,
+        },
+        1 => PhotoSizeSource::Thumbnail {
+            file_type: reader.read_i32("thumbnail file type")? as u32,
+            thumbnail_type: reader.read_i32("thumbnail type")? as u32,
+        },
+        2 => PhotoSizeSource::DialogPhotoSmall {
+            dialog_id: reader.read_i64("dialog id")?,
+            dialog_access_hash: reader.read_i64("dialog access hash")?,
+        },
+        3 => PhotoSizeSource::DialogPhotoBig {
+            dialog_id: reader.read_i64("dialog id")?,
+            dialog_access_hash: reader.read_i64("dialog access hash")?,
+        },
+        4 => PhotoSizeSource::StickerSetThumbnail {
+            sticker_set_id: reader.read_i64("sticker set id")?,
+            sticker_set_access_hash: reader.read_i64("sticker set access hash")?,
+        },
+        5 => PhotoSizeSource::FullLegacy {
+            volume_id: reader.read_i64("volume id")?,
+            secret: reader.read_i64("secret")?,
+            local_id: reader.read_i32("local id")?,
+        },
+        6 => PhotoSizeSource::DialogPhotoSmallLegacy {
+            dialog_id: reader.read_i64("dialog id")?,
+            dialog_access_hash: reader.read_i64("dialog access hash")?,
+            volume_id: reader.read_i64("volume id")?,
+            local_id: reader.read_i32("local id")?,
+        },
+        7 => PhotoSizeSource::DialogPhotoBigLegacy {
+            dialog_id: reader.read_i64("dialog id")?,
+            dialog_access_hash: reader.read_i64("dialog access hash")?,
+            volume_id: reader.read_i64("volume id")?,
+            local_id: reader.read_i32("local id")?,
+        },
+        8 => PhotoSizeSource::StickerSetThumbnailLegacy {
+            sticker_set_id: reader.read_i64("sticker set id")?,
+            sticker_set_access_hash: reader.read_i64("sticker set access hash")?,
+            volume_id: reader.read_i64("volume id")?,
+            local_id: reader.read_i32("local id")?,
+        },
+        9 => PhotoSizeSource::StickerSetThumbnailVersion {
+            sticker_set_id: reader.read_i64("sticker set id")?,
+            sticker_set_access_hash: reader.read_i64("sticker set access hash")?,
+            version: reader.read_i32("sticker set version")?,
+        },
+        other => return Err(FileIdError::UnknownPhotoSizeSource(other)),
+    })
+}
+
+/// Serialize a parsed file_id back into its canonical string form
+pub fn serialize_file_id(parsed: &DecodedFileId) -> String {
+    URL_SAFE_NO_PAD.encode(serialize_file_id_bytes(parsed))
+}
+
+/// Serialize a parsed file_id into its binary form, the bytes behind the base64 text
+pub fn serialize_file_id_bytes(parsed: &DecodedFileId) -> Vec<u8> {
+    let mut writer = Writer::default();
+
+    let mut raw_type_id = parsed.type_id;
+    if parsed.file_reference.is_some() {
+        raw_type_id |= TYPE_ID_FILE_REFERENCE_FLAG;
+    }
+    if parsed.url.is_some() {
+        raw_type_id |= TYPE_ID_WEB_LOCATION_FLAG;
+    }
+
+    writer.write_i32(raw_type_id as i32);
+    writer.write_i32(parsed.dc_id);
+
+    if let Some(file_reference) = &parsed.file_reference {
+        writer.write_bytes(file_reference);
+    }
+
+    if let Some(url) = &parsed.url {
+        writer.write_bytes(url.as_bytes());
+        writer.write_i64(parsed.access_hash);
+    } else {
+        writer.write_i64(parsed.media_id.unwrap_or_default());
+        writer.write_i64(parsed.access_hash);
+
+        if is_photo_type(parsed.type_id) {
+            write_photo_location(&mut writer, parsed);
+        }
+    }
+
+    // The version trailer is not zero-encoded, see split_version
+    let mut data = rle_encode(&writer.data);
+    if parsed.version == PERSISTENT_ID_VERSION {
+        data.push(parsed.sub_version);
+    }
+    data.push(parsed.version);
+
+    data
+}
+
+/// Convert a file_id to its canonical binary form, about 3/4 of the text length
+pub fn file_id_to_bytes(file_id: &str) -> Result<Vec<u8>, FileIdError> {
+    Ok(serialize_file_id_bytes(&parse_file_id(file_id)?))
+}
+
+/// Rebuild the canonical file_id string from its binary form
+pub fn file_id_from_bytes(bytes: &[u8]) -> Result<String, FileIdError> {
+    Ok(parse_file_id(&URL_SAFE_NO_PAD.encode(bytes))?.to_file_id())
+}
+
+impl DecodedFileId {
+    /// The canonical file_id string for this file
+    pub fn to_file_id(&self) -> String {
+        serialize_file_id(self)
+    }
+}
+
+/// Inverse of parse_photo_location
+fn write_photo_location(writer: &mut Writer, parsed: &DecodedFileId) {
+    if let Some(legacy) = &parsed.legacy_photo_location {
+        writer.write_i64(legacy.volume_id);
+    }
+
+    match &parsed.photo_size_source {
+        Some(PhotoSizeSource::Legacy { secret }) if parsed.sub_version < SUB_VERSION_PHOTO_SIZE_SOURCE => {
+            writer.write_i64(*secret);
+        }
+        Some(source) => write_photo_size_source(writer, source),
+        None => {}
+    }
+
+    if let Some(legacy) = &parsed.legacy_photo_location {
+        writer.write_i32(legacy.local_id);
+    }
+}
+
+fn write_photo_size_source(writer: &mut Writer, source: &PhotoSizeSource) {
+    match source {
+        PhotoSizeSource::Legacy { secret } => {
+            writer.write_i32(0);
+            writer.write_i64(*secret);
+        }
+        PhotoSizeSource::Thumbnail { file_type, thumbnail_type } => {
+            writer.write_i32(1);
+            writer.write_i32(*file_type as i32);
+            writer.write_i32(*thumbnail_type as i32);
+        }
+        PhotoSizeSource::DialogPhotoSmall { dialog_id, dialog_access_hash } => {
+            writer.write_i32(2);
+            writer.write_i64(*dialog_id);
+            writer.write_i64(*dialog_access_hash);
+        }
+        PhotoSizeSource::DialogPhotoBig { dialog_id, dialog_access_hash } => {
+            writer.write_i32(3);
+            writer.write_i64(*dialog_id);
+            writer.write_i64(*dialog_access_hash);
+        }
+        PhotoSizeSource::StickerSetThumbnail { sticker_set_id, sticker_set_access_hash } => {
+            writer.write_i32(4);
+            writer.write_i64(*sticker_set_id);
+            writer.write_i64(*sticker_set_access_hash);
+        }
+        PhotoSizeSource::FullLegacy { volume_id, secret, local_id } => {
+            writer.write_i32(5);
+            writer.write_i64(*volume_id);
+            writer.write_i64(*secret);
+            writer.write_i32(*local_id);
+        }
+        PhotoSizeSource::DialogPhotoSmallLegacy { dialog_id, dialog_access_hash, volume_id, local_id } => {
+            writer.write_i32(6);
+            writer.write_i64(*dialog_id);
+            writer.write_i64(*dialog_access_hash);
+            writer.write_i64(*volume_id);
+            writer.write_i32(*local_id);
+        }
+        PhotoSizeSource::DialogPhotoBigLegacy { dialog_id, dialog_access_hash, volume_id, local_id } => {
+            writer.write_i32(7);
+            writer.write_i64(*dialog_id);
+            writer.write_i64(*dialog_access_hash);
+            writer.write_i64(*volume_id);
+            writer.write_i32(*local_id);
+        }
+        PhotoSizeSource::StickerSetThumbnailLegacy { sticker_set_id, sticker_set_access_hash, volume_id, local_id } => {
+            writer.write_i32(8);
+            writer.write_i64(*sticker_set_id);
+            writer.write_i64(*sticker_set_access_hash);
+            writer.write_i64(*volume_id);
+            writer.write_i32(*local_id);
+        }
+        PhotoSizeSource::StickerSetThumbnailVersion { sticker_set_id, sticker_set_access_hash, version } => {
+            writer.write_i32(9);
+            writer.write_i64(*sticker_set_id);
+            writer.write_i64(*sticker_set_access_hash);
+            writer.write_i32(*version);
+        }
+    }
+}
+
+/// Map a normalized type id to a FileType
+/// Stickers always map to FileType::Sticker, the format is only known from the message
+fn file_type_from_id(type_id: u32) -> FileType {
+    match type_id {
+        TYPE_THUMBNAIL => FileType::Thumbnail,
+        TYPE_PROFILE_PHOTO => FileType::ProfilePhoto,
+        TYPE_PHOTO => FileType::Photo,
         TYPE_VOICE => FileType::Voice,
+        TYPE_VIDEO => FileType::Video,
         TYPE_DOCUMENT => FileType::Document,
+        TYPE_ENCRYPTED => FileType::Encrypted,
+        TYPE_TEMP => FileType::Temp,
         TYPE_STICKER => FileType::Sticker,
         TYPE_AUDIO => FileType::Audio,
         TYPE_ANIMATION => FileType::Animation,
+        TYPE_ENCRYPTED_THUMBNAIL => FileType::EncryptedThumbnail,
+        TYPE_WALLPAPER => FileType::Wallpaper,
         TYPE_VIDEO_NOTE => FileType::VideoNote,
+        TYPE_SECURE_RAW => FileType::SecureRaw,
+        TYPE_SECURE => FileType::Secure,
+        TYPE_BACKGROUND => FileType::Background,
+        TYPE_DOCUMENT_AS_FILE => FileType::DocumentAsFile,
+        TYPE_RINGTONE => FileType::Ringtone,
+        TYPE_CALL_LOG => FileType::CallLog,
+        TYPE_PHOTO_STORY => FileType::PhotoStory,
+        TYPE_VIDEO_STORY => FileType::VideoStory,
+        TYPE_SELF_DESTRUCTING_PHOTO => FileType::SelfDestructingPhoto,
+        TYPE_SELF_DESTRUCTING_VIDEO => FileType::SelfDestructingVideo,
+        TYPE_SELF_DESTRUCTING_VIDEO_NOTE => FileType::SelfDestructingVideoNote,
+        TYPE_SELF_DESTRUCTING_VOICE => FileType::SelfDestructingVoice,
         _ => FileType::Unknown,
-    })
+    }
+}
+
+/// Photo-like types store a photo location instead of a plain document location
+fn is_photo_type(type_id: u32) -> bool {
+    matches!(
+        type_id,
+        TYPE_THUMBNAIL
+            | TYPE_PROFILE_PHOTO
+            | TYPE_PHOTO
+            | TYPE_ENCRYPTED_THUMBNAIL
+            | TYPE_WALLPAPER
+            | TYPE_PHOTO_STORY
+            | TYPE_SELF_DESTRUCTING_PHOTO
+    )
 }
 
 /// Normalize type_id by removing flag bits
@@ -79,23 +706,19 @@ fn normalize_type_id(type_id: u32) -> u32 {
 
 /// RLE decode (run-length encoding decode)
 /// Based on Telegram's implementation
-fn rle_decode(data: &[u8]) -> Vec<u8> {
+pub fn rle_decode(data: &[u8]) -> Vec<u8> {
     let mut result = Vec::new();
     let mut i = 0;
 
     while i < data.len() {
-        if data[i] == 0 {
-            // Zero byte indicates a run
-            if i + 1 < data.len() {
-                // Next byte is the count of zeros to insert
-                let count = data[i + 1] as usize;
-                result.extend(vec![0; count]);
-                i += 2;
-            } else {
-                break;
-            }
+        if data[i] == 0 && i + 1 < data.len() {
+            // Zero byte indicates a run, the next byte is the count of zeros to insert
+            let count = data[i + 1] as usize;
+            result.extend(vec![0; count]);
+            i += 2;
         } else {
             // Regular byte, just copy it
+            // A trailing zero without a count is kept as a literal zero, like Telegram does
             result.push(data[i]);
             i += 1;
         }
@@ -104,9 +727,130 @@ fn rle_decode(data: &[u8]) -> Vec<u8> {
     result
 }
 
+/// RLE encode (run-length encoding encode)
+/// Every run of zeros becomes a zero byte followed by the run length
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut zeros = 0u8;
+
+    for &byte in data {
+        if byte == 0 {
+            zeros += 1;
+            if zeros == RLE_MAX_RUN {
+                result.extend([0, zeros]);
+                zeros = 0;
+            }
+        } else {
+            if zeros > 0 {
+                result.extend([0, zeros]);
+                zeros = 0;
+            }
+            result.push(byte);
+        }
+    }
+    if zeros > 0 {
+        result.extend([0, zeros]);
+    }
+
+    result
+}
+
+/// Little-endian writer for the TL-serialized body of a file_id
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn write_i32(&mut self, value: i32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    fn write_i64(&mut self, value: i64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    /// Write a TL byte string, the inverse of Reader::read_bytes
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let header_len = if bytes.len() < 254 {
+            self.data.push(bytes.len() as u8);
+            1
+        } else {
+            self.data.push(254);
+            self.data.extend(&(bytes.len() as u32).to_le_bytes()[..3]);
+            4
+        };
+
+        self.data.extend(bytes);
+        let padding = (4 - (header_len + bytes.len()) % 4) % 4;
+        self.data.extend(std::iter::repeat_n(0, padding));
+    }
+}
+
+/// Little-endian reader for the TL-serialized body of a file_id
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize, what: &'static str) -> Result<&'a [u8], FileIdError> {
+        if self.remaining() < len {
+            return Err(FileIdError::UnexpectedEnd(what));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_i32(&mut self, what: &'static str) -> Result<i32, FileIdError> {
+        let bytes = self.take(4, what)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_i64(&mut self, what: &'static str) -> Result<i64, FileIdError> {
+        let bytes = self.take(8, what)?;
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(bytes);
+        Ok(i64::from_le_bytes(buf))
+    }
+
+    /// Read a TL byte string: a 1 or 4 byte length header, the data and padding to 4 bytes
+    fn read_bytes(&mut self, what: &'static str) -> Result<Vec<u8>, FileIdError> {
+        let first = self.take(1, what)?[0];
+
+        let (len, header_len) = if first == 254 {
+            let len = self.take(3, what)?;
+            (u32::from_le_bytes([len[0], len[1], len[2], 0]) as usize, 4)
+        } else {
+            (first as usize, 1)
+        };
+
+        let bytes = self.take(len, what)?.to_vec();
+        let padding = (4 - (header_len + len) % 4) % 4;
+        self.take(padding, what)?;
+
+        Ok(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    /// A file_id with the given body and version trailer
+    fn to_file_id(body: &[u8], trailer: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode([rle_encode(body).as_slice(), trailer].concat())
+    }
 
     #[test]
     fn test_rle_decode() {
@@ -116,6 +860,19 @@ mod tests {
         assert_eq!(decoded, vec![1, 2, 0, 0, 0, 4, 5]);
     }
 
+    #[test]
+    fn test_rle_decode_trailing_zero() {
+        // A zero byte at the very end has no count and is kept as is
+        assert_eq!(rle_decode(&[7, 0, 2, 9, 0]), vec![7, 0, 0, 9, 0]);
+    }
+
+    #[test]
+    fn test_rle_encode_long_run() {
+        let data = vec![0u8; 300];
+        assert_eq!(rle_encode(&data), vec![0, 250, 0, 50]);
+        assert_eq!(rle_decode(&rle_encode(&data)), data);
+    }
+
     #[test]
     fn test_normalize_type_id() {
         // Test with flags
@@ -125,4 +882,266 @@ mod tests {
         let type_id_with_both_flags = TYPE_STICKER | TYPE_ID_FILE_REFERENCE_FLAG | TYPE_ID_WEB_LOCATION_FLAG;
         assert_eq!(normalize_type_id(type_id_with_both_flags), TYPE_STICKER);
     }
+
+    #[test]
+    fn test_every_known_type_id_has_send_method() {
+        for type_id in 0..=TYPE_SELF_DESTRUCTING_VOICE {
+            let file_type = file_type_from_id(type_id);
+            assert_ne!(file_type, FileType::Unknown, "type id {}", type_id);
+            let is_thumbnail = matches!(type_id, TYPE_THUMBNAIL | TYPE_ENCRYPTED_THUMBNAIL);
+            assert_eq!(file_type.send_method().is_none(), is_thumbnail, "type id {}", type_id);
+        }
+
+        assert_eq!(file_type_from_id(TYPE_SELF_DESTRUCTING_VOICE + 1), FileType::Unknown);
+        assert_eq!(FileType::Unknown.send_method(), None);
+        assert_eq!(FileType::Thumbnail.send_method(), None);
+        assert_eq!(FileType::VideoStory.send_method(), Some(SendMethod::Video));
+        assert_eq!(FileType::Secure.send_method(), Some(SendMethod::Document));
+        assert_eq!(FileType::VideoSticker.send_method(), Some(SendMethod::Sticker));
+    }
+
+    #[test]
+    fn test_file_type_reads_legacy_names() {
+        for (name, file_type) in [
+            ("photo", FileType::Photo),
+            ("voice", FileType::Voice),
+            ("video note", FileType::VideoNote),
+            ("animated sticker", FileType::AnimatedSticker),
+            ("video_sticker", FileType::VideoSticker),
+        ] {
+            let parsed: FileType = serde_json::from_value(serde_json::json!(name)).unwrap();
+            assert_eq!(parsed, file_type, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_send_method_tags() {
+        for method in SendMethod::ALL {
+            assert_eq!(SendMethod::from_tag(method.tag()), Some(method));
+        }
+        // Tags are in encoded payloads, these must never change
+        assert_eq!(SendMethod::Photo.tag(), 0);
+        assert_eq!(SendMethod::Document.tag(), 3);
+        assert_eq!(SendMethod::VideoNote.tag(), 7);
+        assert_eq!(SendMethod::from_tag(8), None);
+    }
+
+    #[test]
+    fn test_parse_document_file_id() {
+        let mut body = Vec::new();
+        body.extend((TYPE_DOCUMENT | TYPE_ID_FILE_REFERENCE_FLAG).to_le_bytes());
+        body.extend(4i32.to_le_bytes());
+        // 3 byte file reference, padded to 4 bytes with its length header
+        body.extend([3, 0xAA, 0xBB, 0xCC]);
+        body.extend(5_234_567_890_123i64.to_le_bytes());
+        body.extend((-42i64).to_le_bytes());
+
+        let parsed = parse_file_id(&to_file_id(&body, &[47, PERSISTENT_ID_VERSION])).unwrap();
+        assert_eq!(parsed.file_type, FileType::Document);
+        assert_eq!(parsed.dc_id, 4);
+        assert_eq!(parsed.file_reference, Some(vec![0xAA, 0xBB, 0xCC]));
+        assert_eq!(parsed.media_id, Some(5_234_567_890_123));
+        assert_eq!(parsed.access_hash, -42);
+        assert_eq!(parsed.photo_size_source, None);
+        assert_eq!((parsed.version, parsed.sub_version), (4, 47));
+    }
+
+    #[test]
+    fn test_parse_photo_thumbnail_file_id() {
+        let mut body = Vec::new();
+        body.extend(TYPE_PHOTO.to_le_bytes());
+        body.extend(2i32.to_le_bytes());
+        body.extend(1i64.to_le_bytes());
+        body.extend(2i64.to_le_bytes());
+        // Thumbnail source with size type 'y'
+        body.extend(1i32.to_le_bytes());
+        body.extend(TYPE_PHOTO.to_le_bytes());
+        body.extend(('y' as u32).to_le_bytes());
+
+        let parsed = parse_file_id(&to_file_id(&body, &[52, PERSISTENT_ID_VERSION])).unwrap();
+        assert_eq!(parsed.file_type, FileType::Photo);
+        assert_eq!(parsed.file_reference, None);
+        assert_eq!(
+            parsed.photo_size_source,
+            Some(PhotoSizeSource::Thumbnail { file_type: TYPE_PHOTO, thumbnail_type: 'y' as u32 })
+        );
+        assert_eq!(parsed.legacy_photo_location, None);
+    }
+
+    #[test]
+    fn test_parse_legacy_profile_photo_file_id() {
+        let mut body = Vec::new();
+        body.extend(TYPE_PROFILE_PHOTO.to_le_bytes());
+        body.extend(1i32.to_le_bytes());
+        body.extend(10i64.to_le_bytes());
+        body.extend(20i64.to_le_bytes());
+        body.extend(777i64.to_le_bytes());
+        // Small dialog photo source
+        body.extend(2i32.to_le_bytes());
+        body.extend((-1001234567890i64).to_le_bytes());
+        body.extend(99i64.to_le_bytes());
+        body.extend(5i32.to_le_bytes());
+
+        let parsed = parse_file_id(&to_file_id(&body, &[24, PERSISTENT_ID_VERSION])).unwrap();
+        assert_eq!(parsed.file_type, FileType::ProfilePhoto);
+        assert_eq!(
+            parsed.photo_size_source,
+            Some(PhotoSizeSource::DialogPhotoSmall { dialog_id: -1001234567890, dialog_access_hash: 99 })
+        );
+        assert_eq!(
+            parsed.legacy_photo_location,
+            Some(LegacyPhotoLocation { volume_id: 777, local_id: 5 })
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_and_unknown_versions() {
+        let mut body = Vec::new();
+        body.extend(TYPE_VIDEO.to_le_bytes());
+        body.extend(4i32.to_le_bytes());
+        assert!(matches!(
+            parse_file_id(&to_file_id(&body, &[47, PERSISTENT_ID_VERSION])),
+            Err(FileIdError::UnexpectedEnd("media id"))
+        ));
+
+        let mut body = Vec::new();
+        body.extend(TYPE_VIDEO.to_le_bytes());
+        assert!(matches!(
+            parse_file_id(&to_file_id(&body, &[9])),
+            Err(FileIdError::UnsupportedVersion(9))
+        ));
+    }
+
+    #[test]
+    fn test_version_trailer_is_not_zero_encoded() {
+        // A document with sub-version 0, written the way TDLib does: the zero-encoded
+        // body, then the sub-version and version bytes as they are. Zero-encoding the
+        // trailer too would turn its 0 into a run and change the id
+        let file_id = "BQADAgADy0SPxMIEAALr4s0b_v___wAE";
+
+        let parsed = parse_file_id(file_id).unwrap();
+        assert_eq!(parsed.file_type, FileType::Document);
+        assert_eq!(parsed.dc_id, 2);
+        assert_eq!(parsed.media_id, Some(5_234_567_890_123));
+        assert_eq!(parsed.access_hash, -8_123_456_789);
+        assert_eq!((parsed.version, parsed.sub_version), (PERSISTENT_ID_VERSION, 0));
+        assert_eq!(parsed.to_file_id(), file_id);
+    }
+
+    #[test]
+    fn test_serialize_long_file_reference() {
+        let parsed = DecodedFileId {
+            type_id: TYPE_DOCUMENT,
+            file_type: FileType::Document,
+            dc_id: 5,
+            file_reference: Some((0..=255).collect()),
+            url: None,
+            media_id: Some(1),
+            access_hash: 2,
+            photo_size_source: None,
+            legacy_photo_location: None,
+            version: PERSISTENT_ID_VERSION,
+            sub_version: 47,
+        };
+
+        let file_id = serialize_file_id(&parsed);
+        assert_eq!(parse_file_id(&file_id).unwrap(), parsed);
+    }
+
+    fn photo_size_source_strategy() -> impl Strategy<Value = PhotoSizeSource> {
+        prop_oneof![
+            any::<i64>().prop_map(|secret| PhotoSizeSource::Legacy { secret }),
+            (any::<u32>(), any::<u32>()).prop_map(|(file_type, thumbnail_type)| {
+                PhotoSizeSource::Thumbnail { file_type, thumbnail_type }
+            }),
+            (any::<i64>(), any::<i64>()).prop_map(|(dialog_id, dialog_access_hash)| {
+                PhotoSizeSource::DialogPhotoSmall { dialog_id, dialog_access_hash }
+            }),
+            (any::<i64>(), any::<i64>()).prop_map(|(dialog_id, dialog_access_hash)| {
+                PhotoSizeSource::DialogPhotoBig { dialog_id, dialog_access_hash }
+            }),
+            (any::<i64>(), any::<i64>()).prop_map(|(sticker_set_id, sticker_set_access_hash)| {
+                PhotoSizeSource::StickerSetThumbnail { sticker_set_id, sticker_set_access_hash }
+            }),
+            (any::<i64>(), any::<i64>(), any::<i32>()).prop_map(|(volume_id, secret, local_id)| {
+                PhotoSizeSource::FullLegacy { volume_id, secret, local_id }
+            }),
+            (any::<i64>(), any::<i64>(), any::<i64>(), any::<i32>()).prop_map(
+                |(dialog_id, dialog_access_hash, volume_id, local_id)| {
+                    PhotoSizeSource::DialogPhotoSmallLegacy { dialog_id, dialog_access_hash, volume_id, local_id }
+                }
+            ),
+            (any::<i64>(), any::<i64>(), any::<i64>(), any::<i32>()).prop_map(
+                |(dialog_id, dialog_access_hash, volume_id, local_id)| {
+                    PhotoSizeSource::DialogPhotoBigLegacy { dialog_id, dialog_access_hash, volume_id, local_id }
+                }
+            ),
+            (any::<i64>(), any::<i64>(), any::<i64>(), any::<i32>()).prop_map(
+                |(sticker_set_id, sticker_set_access_hash, volume_id, local_id)| {
+                    PhotoSizeSource::StickerSetThumbnailLegacy { sticker_set_id, sticker_set_access_hash, volume_id, local_id }
+                }
+            ),
+            (any::<i64>(), any::<i64>(), any::<i32>()).prop_map(
+                |(sticker_set_id, sticker_set_access_hash, version)| {
+                    PhotoSizeSource::StickerSetThumbnailVersion { sticker_set_id, sticker_set_access_hash, version }
+                }
+            ),
+        ]
+    }
+
+    /// Structurally valid file_ids of every layout the parser understands
+    fn decoded_file_id_strategy() -> impl Strategy<Value = DecodedFileId> {
+        (
+            0u32..26,
+            any::<i32>(),
+            proptest::option::of(proptest::collection::vec(any::<u8>(), 0..300)),
+            proptest::option::of("[a-z0-9:/._-]{1,80}"),
+            any::<i64>(),
+            any::<i64>(),
+            photo_size_source_strategy(),
+            (any::<i64>(), any::<i32>()),
+            prop_oneof![Just((PERSISTENT_ID_VERSION_OLD, 0u8)), any::<u8>().prop_map(|sub| (PERSISTENT_ID_VERSION, sub))],
+        )
+            .prop_map(|(type_id, dc_id, file_reference, url, media_id, access_hash, source, (volume_id, local_id), (version, sub_version))| {
+                let is_photo = url.is_none() && is_photo_type(type_id);
+                let photo_size_source = is_photo.then_some(if sub_version >= SUB_VERSION_PHOTO_SIZE_SOURCE {
+                    source
+                } else {
+                    PhotoSizeSource::Legacy { secret: access_hash ^ volume_id }
+                });
+                let legacy_photo_location = (is_photo && sub_version < SUB_VERSION_NO_VOLUME_ID)
+                    .then_some(LegacyPhotoLocation { volume_id, local_id });
+
+                DecodedFileId {
+                    type_id,
+                    file_type: file_type_from_id(type_id),
+                    dc_id,
+                    file_reference,
+                    media_id: url.is_none().then_some(media_id),
+                    url,
+                    access_hash,
+                    photo_size_source,
+                    legacy_photo_location,
+                    version,
+                    sub_version,
+                }
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_rle_round_trip(data in proptest::collection::vec(prop_oneof![Just(0u8), any::<u8>()], 0..600)) {
+            prop_assert_eq!(rle_decode(&rle_encode(&data)), data);
+        }
+
+        #[test]
+        fn prop_parse_serialize_round_trip(parsed in decoded_file_id_strategy()) {
+            let file_id = serialize_file_id(&parsed);
+            let reparsed = parse_file_id(&file_id).unwrap();
+
+            prop_assert_eq!(&reparsed, &parsed);
+            // Serializing the parsed id must give back the exact same string
+            prop_assert_eq!(reparsed.to_file_id(), file_id);
+        }
+    }
 }
@@ -0,0 +1,276 @@
+//! Telegram file_unique_id decoder
+//!
+//! Unlike a file_id, a file_unique_id is the same for every bot and never
+//! changes, so it identifies the underlying media itself. It cannot be used
+//! to download or resend a file.
+//! Unique IDs are base64url encoded and RLE (run-length) encoded, like file_ids.
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use crate::utils::file_id_decoder::{parse_file_id, rle_decode, rle_encode, DecodedFileId, FileIdError, FileType, PhotoSizeSource};
+
+/// Kind of media a unique id belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileUniqueType {
+    Web,
+    Photo,
+    Document,
+    Secure,
+    Encrypted,
+    Temp,
+    Unknown(i32),
+}
+
+impl FileUniqueType {
+    fn from_id(type_id: i32) -> Self {
+        match type_id {
+            0 => FileUniqueType::Web,
+            1 => FileUniqueType::Photo,
+            2 => FileUniqueType::Document,
+            3 => FileUniqueType::Secure,
+            4 => FileUniqueType::Encrypted,
+            5 => FileUniqueType::Temp,
+            other => FileUniqueType::Unknown(other),
+        }
+    }
+
+    fn id(self) -> i32 {
+        match self {
+            FileUniqueType::Web => 0,
+            FileUniqueType::Photo => 1,
+            FileUniqueType::Document => 2,
+            FileUniqueType::Secure => 3,
+            FileUniqueType::Encrypted => 4,
+            FileUniqueType::Temp => 5,
+            FileUniqueType::Unknown(id) => id,
+        }
+    }
+}
+
+/// Everything a Telegram file_unique_id contains
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFileUniqueId {
+    pub unique_type: FileUniqueType,
+    /// Set for web files
+    pub url: Option<String>,
+    pub media_id: Option<i64>,
+    /// Size letter of a photo thumbnail ('s', 'm', 'x', ...), for newer photo ids
+    pub photo_size: Option<u8>,
+    /// Set for legacy photo ids, which have no media id
+    pub volume_id: Option<i64>,
+    pub local_id: Option<i32>,
+}
+
+impl DecodedFileUniqueId {
+    /// Short human-readable description
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!("{:?}", self.unique_type)];
+
+        if let Some(url) = &self.url {
+            parts.push(format!("url {}", url));
+        }
+        if let Some(media_id) = self.media_id {
+            parts.push(format!("media id {}", media_id));
+        }
+        if let Some(size) = self.photo_size {
+            parts.push(format!("size '{}'", size as char));
+        }
+        if let (Some(volume_id), Some(local_id)) = (self.volume_id, self.local_id) {
+            parts.push(format!("volume id {}, local id {}", volume_id, local_id));
+        }
+
+        parts.join(", ")
+    }
+}
+
+/// Parse a Telegram file_unique_id
+pub fn parse_file_unique_id(file_unique_id: &str) -> Result<DecodedFileUniqueId, FileIdError> {
+    let decoded = URL_SAFE_NO_PAD.decode(file_unique_id.trim())?;
+    let data = rle_decode(&decoded);
+
+    if data.len() < 4 {
+        return Err(FileIdError::TooShort);
+    }
+
+    let unique_type = FileUniqueType::from_id(i32::from_le_bytes([data[0], data[1], data[2], data[3]]));
+    let body = &data[4..];
+
+    let mut parsed = DecodedFileUniqueId {
+        unique_type,
+        url: None,
+        media_id: None,
+        photo_size: None,
+        volume_id: None,
+        local_id: None,
+    };
+
+    if unique_type == FileUniqueType::Web {
+        // A TL string: 1 byte length followed by the url
+        let (&len, url) = body.split_first().ok_or(FileIdError::UnexpectedEnd("web url"))?;
+        let url = url.get(..len as usize).ok_or(FileIdError::UnexpectedEnd("web url"))?;
+        parsed.url = Some(String::from_utf8(url.to_vec()).map_err(|_| FileIdError::InvalidUrl)?);
+        return Ok(parsed);
+    }
+
+    match body.len() {
+        // Documents and most media: the media id
+        8 => parsed.media_id = Some(read_i64(body)),
+        // Newer photo sizes: the photo id followed by the size letter
+        9 => {
+            parsed.media_id = Some(read_i64(body));
+            parsed.photo_size = Some(body[8]);
+        }
+        // Legacy photos: volume id and local id
+        12 => {
+            parsed.volume_id = Some(read_i64(body));
+            parsed.local_id = Some(i32::from_le_bytes([body[8], body[9], body[10], body[11]]));
+        }
+        0..8 => return Err(FileIdError::UnexpectedEnd("media id")),
+        len => return Err(FileIdError::TrailingBytes(len - 8)),
+    }
+
+    Ok(parsed)
+}
+
+/// The file_unique_id of the file a file_id points to
+/// None for web files and photo sizes whose unique id is not derived here
+pub fn file_unique_id_of(file_id: &str) -> Option<String> {
+    unique_id_of(&parse_file_id(file_id).ok()?)
+}
+
+/// Build the unique id from the parts of a file_id, inverse of parse_file_unique_id
+fn unique_id_of(parsed: &DecodedFileId) -> Option<String> {
+    if parsed.url.is_some() {
+        return None;
+    }
+
+    let unique_type = match parsed.file_type {
+        FileType::Thumbnail
+        | FileType::ProfilePhoto
+        | FileType::Photo
+        | FileType::EncryptedThumbnail
+        | FileType::Wallpaper
+        | FileType::PhotoStory
+        | FileType::SelfDestructingPhoto => FileUniqueType::Photo,
+        FileType::Secure | FileType::SecureRaw => FileUniqueType::Secure,
+        FileType::Encrypted => FileUniqueType::Encrypted,
+        FileType::Temp => FileUniqueType::Temp,
+        FileType::Unknown => return None,
+        _ => FileUniqueType::Document,
+    };
+
+    let mut data = Vec::from(unique_type.id().to_le_bytes());
+    if unique_type != FileUniqueType::Photo {
+        data.extend(parsed.media_id?.to_le_bytes());
+    } else if let Some(PhotoSizeSource::Thumbnail { thumbnail_type, .. }) = &parsed.photo_size_source {
+        // Photo sizes: the photo id followed by the size letter
+        data.extend(parsed.media_id?.to_le_bytes());
+        data.push(u8::try_from(*thumbnail_type).ok().filter(u8::is_ascii)?);
+    } else if let Some(legacy) = &parsed.legacy_photo_location {
+        data.extend(legacy.volume_id.to_le_bytes());
+        data.extend(legacy.local_id.to_le_bytes());
+    } else if let Some(PhotoSizeSource::FullLegacy { volume_id, local_id, .. }) = &parsed.photo_size_source {
+        data.extend(volume_id.to_le_bytes());
+        data.extend(local_id.to_le_bytes());
+    } else {
+        return None;
+    }
+
+    Some(URL_SAFE_NO_PAD.encode(rle_encode(&data)))
+}
+
+fn read_i64(bytes: &[u8]) -> i64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    i64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::file_id_decoder::rle_encode;
+
+    fn to_unique_id(body: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(rle_encode(body))
+    }
+
+    #[test]
+    fn test_parse_document_unique_id() {
+        let mut body = Vec::new();
+        body.extend(2i32.to_le_bytes());
+        body.extend(5_234_567_890_123i64.to_le_bytes());
+
+        let parsed = parse_file_unique_id(&to_unique_id(&body)).unwrap();
+        assert_eq!(parsed.unique_type, FileUniqueType::Document);
+        assert_eq!(parsed.media_id, Some(5_234_567_890_123));
+        assert_eq!(parsed.photo_size, None);
+    }
+
+    #[test]
+    fn test_parse_photo_size_unique_id() {
+        let mut body = Vec::new();
+        body.extend(1i32.to_le_bytes());
+        body.extend(42i64.to_le_bytes());
+        body.push(b'y');
+
+        let parsed = parse_file_unique_id(&to_unique_id(&body)).unwrap();
+        assert_eq!(parsed.unique_type, FileUniqueType::Photo);
+        assert_eq!(parsed.media_id, Some(42));
+        assert_eq!(parsed.photo_size, Some(b'y'));
+    }
+
+    #[test]
+    fn test_parse_legacy_photo_unique_id() {
+        let mut body = Vec::new();
+        body.extend(1i32.to_le_bytes());
+        body.extend(123_456i64.to_le_bytes());
+        body.extend(7i32.to_le_bytes());
+
+        let parsed = parse_file_unique_id(&to_unique_id(&body)).unwrap();
+        assert_eq!(parsed.media_id, None);
+        assert_eq!(parsed.volume_id, Some(123_456));
+        assert_eq!(parsed.local_id, Some(7));
+    }
+
+    #[test]
+    fn test_unique_id_of_file_id() {
+        let document = DecodedFileId {
+            type_id: 5,
+            file_type: FileType::Document,
+            dc_id: 2,
+            file_reference: Some(vec![1, 0, 0, 0, 42]),
+            url: None,
+            media_id: Some(5_234_567_890_123),
+            access_hash: -8_123_456_789,
+            photo_size_source: None,
+            legacy_photo_location: None,
+            version: 4,
+            sub_version: 47,
+        };
+
+        let unique_id = file_unique_id_of(&document.to_file_id()).unwrap();
+        let parsed = parse_file_unique_id(&unique_id).unwrap();
+        assert_eq!(parsed.unique_type, FileUniqueType::Document);
+        assert_eq!(parsed.media_id, Some(5_234_567_890_123));
+
+        let photo = DecodedFileId {
+            type_id: 2,
+            file_type: FileType::Photo,
+            media_id: Some(42),
+            photo_size_source: Some(PhotoSizeSource::Thumbnail { file_type: 2, thumbnail_type: 'y' as u32 }),
+            ..document
+        };
+
+        let unique_id = file_unique_id_of(&photo.to_file_id()).unwrap();
+        let parsed = parse_file_unique_id(&unique_id).unwrap();
+        assert_eq!(parsed.unique_type, FileUniqueType::Photo);
+        assert_eq!((parsed.media_id, parsed.photo_size), (Some(42), Some(b'y')));
+
+        assert_eq!(file_unique_id_of("not a file id"), None);
+    }
+
+    #[test]
+    fn test_parse_invalid_unique_id() {
+        assert!(parse_file_unique_id("!!").is_err());
+        assert!(matches!(parse_file_unique_id(&to_unique_id(&[2, 0, 0, 0, 1])), Err(FileIdError::UnexpectedEnd(_))));
+    }
+}
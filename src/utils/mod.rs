@@ -1,7 +1,15 @@
+pub mod callback_data;
+pub mod compression;
+pub mod crypto;
 pub mod encoder;
 pub mod emojis;
 pub mod file_id_decoder;
+pub mod file_unique_id;
+pub mod query_options;
 
-pub use encoder::{encode, encode_file_id, decode_with_file_check};
-pub use emojis::{EMOJI_LIST, get_random_emoji};
-pub use file_id_decoder::{decode_file_type, FileType};
+pub use callback_data::CallbackAction;
+pub use encoder::{encode, encode_file, encode_album, encode_content, decode_payload, has_variation_selectors, DecodedPayload, EncodedFile, EncoderError, MessageOrigin, SharedContent, TextPayload};
+pub use emojis::{EmojiCategory, emoji_catalog, find_emoji, get_random_emoji, search_emojis, single_emoji, split_leading_emoji, split_leading_keyword};
+pub use file_id_decoder::{decode_file_type, parse_file_id, FileType, SendMethod};
+pub use file_unique_id::{file_unique_id_of, parse_file_unique_id};
+pub use query_options::{split_options, OptionError, OPTIONS_HELP};
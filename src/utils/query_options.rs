@@ -0,0 +1,151 @@
+//! Option flags at the start of an inline query
+//!
+//! `🔥 pw:hunter2 ttl:1d z:on your text` hides "your text" behind a password,
+//! lets it expire after a day and compresses it. Flags are `name:value` words
+//! before the text, the first other word starts the text. A `--` word ends the
+//! flags, so `-- re:meeting` hides "re:meeting".
+
+use chrono::TimeDelta;
+use thiserror::Error;
+use crate::utils::encoder::{CarrierScheme, EncodeOptions};
+
+/// Longest time a message can be set to expire after
+const MAX_TTL_DAYS: i64 = 365;
+
+/// Shown when the flags can't be parsed
+pub const OPTIONS_HELP: &str = "pw:<password> - needs the password to read\n\
+    ttl:<time> - expires after e.g. 30m, 12h, 1d or 2w\n\
+    z:on - compresses long text\n\
+    s:tag - hides with tag characters instead of variation selectors\n\
+    -- ends the options, e.g. -- re:meeting hides text starting with a colon word";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum OptionError {
+    #[error("Unknown option {0}:")]
+    UnknownFlag(String),
+    #[error("Invalid value for {flag}: {value}")]
+    InvalidValue { flag: String, value: String },
+}
+
+/// Split the leading flags off a query, returning the options and the rest of the text
+pub fn split_options(text: &str) -> Result<(EncodeOptions, &str), OptionError> {
+    let mut options = EncodeOptions::default();
+    let mut rest = text.trim_start();
+
+    while let Some((flag, value)) = leading_flag(rest) {
+        let invalid = || OptionError::InvalidValue {
+            flag: flag.to_string(),
+            value: value.to_string(),
+        };
+
+        match flag {
+            "pw" | "password" => options.password = Some(value.to_string()),
+            "ttl" | "expires" => options.ttl = Some(parse_ttl(value).ok_or_else(invalid)?),
+            "z" | "zip" | "compress" => options.compress = parse_switch(value).ok_or_else(invalid)?,
+            "s" | "scheme" => options.scheme = parse_scheme(value).ok_or_else(invalid)?,
+            _ => return Err(OptionError::UnknownFlag(flag.to_string())),
+        }
+
+        rest = rest[flag.len() + 1 + value.len()..].trim_start();
+    }
+
+    if let Some(escaped) = rest.strip_prefix("--")
+        && (escaped.is_empty() || escaped.starts_with(char::is_whitespace))
+    {
+        rest = escaped.trim_start();
+    }
+
+    Ok((options, rest))
+}
+
+/// The `name:value` word text starts with, if any
+/// Links like https://example.com are text, not flags
+fn leading_flag(text: &str) -> Option<(&str, &str)> {
+    let word = text.split_whitespace().next()?;
+    let (flag, value) = word.split_once(':')?;
+
+    let is_flag = !flag.is_empty()
+        && flag.chars().all(|c| c.is_ascii_lowercase())
+        && !value.is_empty()
+        && !value.starts_with("//");
+
+    is_flag.then_some((flag, value))
+}
+
+/// A time span like 45s, 30m, 12h, 1d or 2w
+fn parse_ttl(value: &str) -> Option<TimeDelta> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: i64 = value[..split].parse().ok().filter(|&amount| amount > 0)?;
+
+    let ttl = match &value[split..] {
+        "s" => TimeDelta::try_seconds(amount),
+        "m" => TimeDelta::try_minutes(amount),
+        "h" => TimeDelta::try_hours(amount),
+        "d" => TimeDelta::try_days(amount),
+        "w" => TimeDelta::try_weeks(amount),
+        _ => None,
+    }?;
+
+    (ttl <= TimeDelta::days(MAX_TTL_DAYS)).then_some(ttl)
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" | "yes" | "1" | "true" => Some(true),
+        "off" | "no" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_scheme(value: &str) -> Option<CarrierScheme> {
+    match value {
+        "vs" | "selectors" => Some(CarrierScheme::Selectors),
+        "tag" | "tags" => Some(CarrierScheme::Tags),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::encoder::{decode, encode};
+
+    #[test]
+    fn test_split_options() {
+        let (options, text) = split_options("pw:hunter2 ttl:1d z:on s:tag your text: here").unwrap();
+        assert_eq!(options.password.as_deref(), Some("hunter2"));
+        assert_eq!(options.ttl, Some(TimeDelta::days(1)));
+        assert!(options.compress);
+        assert_eq!(options.scheme, CarrierScheme::Tags);
+        assert_eq!(text, "your text: here");
+
+        let (options, text) = split_options("see https://example.com").unwrap();
+        assert_eq!(options, EncodeOptions::default());
+        assert_eq!(text, "see https://example.com");
+    }
+
+    #[test]
+    fn test_escaped_text() {
+        let (options, text) = split_options("-- re:meeting at noon").unwrap();
+        assert_eq!(options, EncodeOptions::default());
+        assert_eq!(text, "re:meeting at noon");
+        assert_eq!(decode(&encode("🔥", text).unwrap()).unwrap(), "re:meeting at noon");
+
+        let (options, text) = split_options("pw:hunter2 -- note:buy milk").unwrap();
+        assert_eq!(options.password.as_deref(), Some("hunter2"));
+        assert_eq!(text, "note:buy milk");
+
+        // Only a standalone -- is an escape
+        let (_, text) = split_options("--flag text").unwrap();
+        assert_eq!(text, "--flag text");
+        assert_eq!(split_options("todo:x"), Err(OptionError::UnknownFlag("todo".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert_eq!(split_options("pq:hunter2 text"), Err(OptionError::UnknownFlag("pq".to_string())));
+        assert!(matches!(split_options("ttl:forever text"), Err(OptionError::InvalidValue { .. })));
+        assert!(matches!(split_options("ttl:0d text"), Err(OptionError::InvalidValue { .. })));
+        assert!(matches!(split_options("z:maybe text"), Err(OptionError::InvalidValue { .. })));
+    }
+}